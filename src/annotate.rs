@@ -0,0 +1,466 @@
+//! A runtime alternative to `#[annotate_error]` for code that can't use `nom-plus-macros`.
+//!
+//! Wrapping a parser in [`annotate`] records where the wrapping happened through `#[track_caller]`.
+//! When the parser fails, the statement at that location is read back from the source file and
+//! captured the same way the attribute would have captured it:
+//!
+//! ```ignore
+//! fn greeting(input: &str) -> IResult<&str, &str, ContextError> {
+//!     let (input, _) = annotate(tag("hello "))(input)?;
+//!     annotate(tag("world"))(input)
+//! }
+//! ```
+//!
+//! The capture is best effort: it needs the source file to be readable at the path `file!()` reports,
+//! relative to the working directory, and only sees the annotated statement and the signature of the
+//! function it is in.
+
+use crate::profile::ProfileKey;
+use crate::trace;
+use crate::ContextError;
+#[cfg(debug_assertions)]
+use crate::{ContextFrame, FunctionContext, ParserSourceCapture, SourceCapture};
+use nom::{IResult, InputLength, Parser};
+#[cfg(debug_assertions)]
+use proc_macro2::TokenStream;
+#[cfg(debug_assertions)]
+use quote::ToTokens;
+use std::panic::Location;
+#[cfg(debug_assertions)]
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock, PoisonError},
+};
+#[cfg(debug_assertions)]
+use syn::{parse::ParseStream, spanned::Spanned, Expr, Stmt};
+
+/// How many lines an annotated statement may span, e.g. after being wrapped by rustfmt.
+#[cfg(debug_assertions)]
+const MAX_STATEMENT_LINES: usize = 8;
+
+/// The contexts captured so far, read from the source file once per call site.
+#[cfg(debug_assertions)]
+static CAPTURED_CALL_SITES: OnceLock<Mutex<HashMap<&'static Location<'static>, FunctionContext>>> =
+    OnceLock::new();
+
+/// Attaches the source of the call site to the errors of `parser`, e.g.
+/// `let (input, _) = annotate(tag("world"))(input)?;`.
+///
+/// Errors that don't carry a context yet receive the call site's context, while errors that were
+/// already annotated by a nested parser record the call site as an outer frame of the parser-chain.
+#[track_caller]
+#[allow(clippy::result_large_err)]
+pub fn annotate<'a, Output, ParserType>(
+    mut parser: ParserType,
+) -> impl FnMut(&'a str) -> IResult<&'a str, Output, ContextError<'a>>
+where
+    ParserType: Parser<&'a str, Output, ContextError<'a>>,
+{
+    let location = Location::caller();
+    move |input: &'a str| {
+        let parse = |input: &'a str| {
+            let result = parser.parse(input);
+            #[cfg(debug_assertions)]
+            let result = result.map_err(|err| {
+                err.map(|mut error| {
+                    attach_call_site(&mut error, location, input);
+                    error
+                })
+            });
+            result
+        };
+        let describe = || {
+            let call_site = format!("{}:{}", location.file(), location.line());
+            #[cfg(debug_assertions)]
+            {
+                let context = call_site_context(location);
+                // Profiled per call site, so the statements of a function are told apart
+                let function = match context.qualified_name() {
+                    Some(qualified_name) => format!("{qualified_name}@{call_site}"),
+                    None => call_site,
+                };
+                let name = context
+                    .parser_contexts
+                    .and_then(|parser_contexts| {
+                        Some(parser_contexts.first()?.ident.source_text.clone())
+                    })
+                    .unwrap_or_else(|| "annotate".to_string());
+                // Every wrapped statement of a function is told apart by the line it is on
+                trace::Description {
+                    name,
+                    function,
+                    file: Some(location.file()),
+                    line: Some(location.line() as usize),
+                }
+            }
+            #[cfg(not(debug_assertions))]
+            trace::Description {
+                name: "annotate".to_string(),
+                function: call_site,
+                file: Some(location.file()),
+                line: Some(location.line() as usize),
+            }
+        };
+        trace::observe(
+            ProfileKey::CallSite(location),
+            describe,
+            trace::FunctionSpan::parser,
+            input,
+            parse,
+        )
+    }
+}
+
+/// The context captured for the call site at `location`, read from the source file on first use.
+#[cfg(debug_assertions)]
+fn call_site_context(location: &'static Location<'static>) -> FunctionContext {
+    CAPTURED_CALL_SITES
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .entry(location)
+        .or_insert_with(|| capture_call_site(location))
+        .clone()
+}
+
+#[cfg(debug_assertions)]
+fn attach_call_site<'a>(
+    error: &mut ContextError<'a>,
+    location: &'static Location<'static>,
+    input: &'a str,
+) {
+    let context = call_site_context(location);
+    if error.context.parser_contexts.is_none() && error.chain.is_empty() {
+        error.set_context(context);
+        error.file = Some(location.file());
+        if error.input.is_none() {
+            error.set_input(input);
+        }
+    } else {
+        error.push_frame(
+            ContextFrame::new(context)
+                .set_file(location.file())
+                .set_input(input),
+        );
+    }
+}
+
+/// Reads the annotated statement and the signature of the function it is in from the source file,
+/// leaving the context empty when the file can't be read.
+#[cfg(debug_assertions)]
+fn capture_call_site(location: &Location) -> FunctionContext {
+    let mut context = FunctionContext::default();
+    let Ok(source) = std::fs::read_to_string(location.file()) else {
+        return context;
+    };
+    let lines = source.lines().collect::<Vec<_>>();
+    let line_index = (location.line() as usize).saturating_sub(1);
+    let column = (location.column() as usize).saturating_sub(1);
+
+    if let Some(signature) = enclosing_signature(&lines, line_index) {
+        context.signature = signature;
+    }
+    if let Some(mut parser_context) = capture_statement(&lines, line_index, column) {
+        shift_lines(&mut parser_context, line_index);
+        context.set_parser_context(parser_context);
+    }
+    context.set_parser_context_failure_index(0)
+}
+
+/// Captures the parser expression starting at `column` of the line at `line_index`, including the
+/// binding pattern when it is the initializer of a `let` statement. Line numbers are relative to the
+/// line at `line_index`, which is line 1.
+#[cfg(debug_assertions)]
+fn capture_statement(
+    lines: &[&str],
+    line_index: usize,
+    column: usize,
+) -> Option<ParserSourceCapture> {
+    let first_line = lines.get(line_index)?;
+    let last_line_index = (line_index + MAX_STATEMENT_LINES).min(lines.len());
+    (line_index + 1..=last_line_index).find_map(|end| {
+        let text = lines[line_index..end].join("\n");
+        let statement = syn::parse_str::<Stmt>(&text)
+            .ok()
+            .and_then(|statement| match statement {
+                Stmt::Local(local)
+                    if local.init.as_ref().is_some_and(|init| {
+                        let start = init.expr.span().start();
+                        start.line == 1 && start.column == column
+                    }) =>
+                {
+                    ParserSourceCapture::from_local(&local)
+                }
+                _ => None,
+            });
+        // Blanking out what precedes the call keeps the columns while parsing only the expression
+        let blanked = first_line
+            .char_indices()
+            .nth(column)
+            .map(|(offset, _)| format!("{}{}", " ".repeat(column), &text[offset..]))?;
+        statement.or_else(|| {
+            let expr = syn::parse::Parser::parse_str(
+                |stream: ParseStream| {
+                    let expr = stream.parse::<Expr>()?;
+                    stream.parse::<TokenStream>()?;
+                    Ok(expr)
+                },
+                &blanked,
+            )
+            .ok()?;
+            // `annotate(tag("("))` passed on to a combinator isn't invoked on an input itself
+            let annotated_parser = crate::annotated_parser(&expr);
+            if std::ptr::eq(annotated_parser, &expr) {
+                ParserSourceCapture::from_expr(&expr)
+            } else {
+                let mut capture = ParserSourceCapture::from_parser(annotated_parser)?;
+                capture.expression = crate::capture_tokens(expr.to_token_stream());
+                Some(capture)
+            }
+        })
+    })
+}
+
+/// The signature of the function containing the line at `line_index`, found by looking upwards for
+/// the nearest function header. Signatures wrapped over several lines, e.g. by rustfmt, extend to the
+/// line opening the function body.
+#[cfg(debug_assertions)]
+fn enclosing_signature(lines: &[&str], line_index: usize) -> Option<SourceCapture> {
+    let lines = &lines[..=line_index.min(lines.len().checked_sub(1)?)];
+    let first_index = lines.iter().rposition(|line| {
+        // Qualifiers and visibility may precede the `fn` keyword, e.g. `pub(crate) const fn`
+        line.split_whitespace().find(|word| {
+            !(word.starts_with("pub") || matches!(*word, "async" | "const" | "unsafe"))
+        }) == Some("fn")
+    })?;
+    let last_index = (first_index..lines.len())
+        .find(|index| lines[*index].trim_end().ends_with('{'))
+        .unwrap_or(first_index);
+    Some(SourceCapture {
+        source_text: lines[first_index..=last_index].join("\n"),
+        line_number: first_index + 1,
+        end_line_number: (last_index > first_index).then_some(last_index + 1),
+        ..SourceCapture::default()
+    })
+}
+
+/// Moves every capture of `parser_context` down by `offset` lines.
+#[cfg(debug_assertions)]
+fn shift_lines(parser_context: &mut ParserSourceCapture, offset: usize) {
+    let captures = parser_context
+        .binding_pattern
+        .iter_mut()
+        .chain([
+            &mut parser_context.ident,
+            &mut parser_context.pattern,
+            &mut parser_context.input,
+        ])
+        .chain(parser_context.expression.iter_mut())
+        .chain(parser_context.nested_parsers.iter_mut().flatten());
+    for capture in captures {
+        capture.line_number += offset;
+        capture.end_line_number = capture
+            .end_line_number
+            .map(|end_line_number| end_line_number + offset);
+    }
+}
+
+/// Wraps a parser to describe its failures without writing closures, e.g.
+/// `Annotated::new(delimited(tag("["), alpha1, tag("]"))).name("header").expect("a section header like [name]")`.
+///
+/// The descriptions are only filled in when the error doesn't carry them yet, so the innermost
+/// `Annotated` parser that failed wins.
+#[derive(Clone, Debug)]
+pub struct Annotated<'a, ParserType> {
+    pub parser: ParserType,
+    pub name: Option<&'a str>,
+    pub expectation: Option<&'a str>,
+    pub label: Option<&'a str>,
+    pub help: Option<&'a str>,
+    /// Where the parser was created, which its profile is keyed by.
+    pub location: &'static Location<'static>,
+}
+
+impl<'a, ParserType> Annotated<'a, ParserType> {
+    #[must_use]
+    #[track_caller]
+    pub fn new(parser: ParserType) -> Self {
+        Self {
+            parser,
+            name: None,
+            expectation: None,
+            label: None,
+            help: None,
+            location: Location::caller(),
+        }
+    }
+
+    /// Sets what the parser is called in the grammar, e.g. `header`.
+    #[must_use]
+    pub fn name(mut self, name: &'a str) -> Self {
+        self.name = Some(name);
+        self
+    }
+
+    /// Sets a description of what the parser expects, shown in place of the captured pattern.
+    #[must_use]
+    pub fn expect(mut self, expectation: &'a str) -> Self {
+        self.expectation = Some(expectation);
+        self
+    }
+
+    /// Sets the label of the failing span, `error occurred here` by default.
+    #[must_use]
+    pub fn label(mut self, label: &'a str) -> Self {
+        self.label = Some(label);
+        self
+    }
+
+    /// Sets a hint on how to fix the input.
+    #[must_use]
+    pub fn help(mut self, help: &'a str) -> Self {
+        self.help = Some(help);
+        self
+    }
+
+    #[cfg(debug_assertions)]
+    fn describe(&self, error: &mut ContextError<'a>) {
+        error.parser_name = error.parser_name.or(self.name);
+        error.expectation = error.expectation.or(self.expectation);
+        error.label = error.label.or(self.label);
+        error.help = error.help.or(self.help);
+    }
+}
+
+impl<'a, Input, Output, ParserType> Parser<Input, Output, ContextError<'a>>
+    for Annotated<'a, ParserType>
+where
+    Input: InputLength,
+    ParserType: Parser<Input, Output, ContextError<'a>>,
+{
+    #[allow(clippy::result_large_err)]
+    fn parse(&mut self, input: Input) -> IResult<Input, Output, ContextError<'a>> {
+        let name = self.name.unwrap_or("Annotated");
+        let location = self.location;
+        trace::observe(
+            ProfileKey::CallSite(location),
+            || trace::Description {
+                name: name.to_string(),
+                function: format!("{name}@{}:{}", location.file(), location.line()),
+                file: None,
+                line: None,
+            },
+            trace::FunctionSpan::parser,
+            input,
+            |input| {
+                let result = self.parser.parse(input);
+                #[cfg(debug_assertions)]
+                let result = result.map_err(|err| {
+                    err.map(|mut error| {
+                        self.describe(&mut error);
+                        error
+                    })
+                });
+                result
+            },
+        )
+    }
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use super::*;
+    use nom::bytes::complete::tag;
+
+    fn parse_error<'a>(
+        mut parser: impl Parser<&'a str, &'a str, ContextError<'a>>,
+        input: &'a str,
+    ) -> ContextError<'a> {
+        match parser.parse(input) {
+            Err(nom::Err::Error(error)) => error,
+            _ => panic!("expected an error"),
+        }
+    }
+
+    #[test]
+    fn annotated_fills_in_the_descriptions() {
+        let error = parse_error(
+            Annotated::new(tag("["))
+                .name("header")
+                .expect("a section header like [name]")
+                .label("header expected here")
+                .help("sections start with ["),
+            "name]",
+        );
+        assert_eq!(error.parser_name, Some("header"));
+        assert_eq!(error.expectation, Some("a section header like [name]"));
+        assert_eq!(error.label, Some("header expected here"));
+        assert_eq!(error.help, Some("sections start with ["));
+    }
+
+    #[test]
+    fn annotated_keeps_the_innermost_descriptions() {
+        let inner = Annotated::new(tag("["))
+            .name("open")
+            .expect("an opening bracket");
+        let error = parse_error(
+            Annotated::new(inner)
+                .name("header")
+                .expect("a section header")
+                .help("sections start with ["),
+            "name]",
+        );
+        assert_eq!(error.parser_name, Some("open"));
+        assert_eq!(error.expectation, Some("an opening bracket"));
+        assert_eq!(error.label, None);
+        assert_eq!(error.help, Some("sections start with ["));
+    }
+
+    #[test]
+    fn annotated_errors_without_a_context_render_without_headers() {
+        let error = parse_error(Annotated::new(tag("[")).name("header"), "name]");
+        let rendered = format!("{error:?}");
+        assert!(!rendered.contains("-->"), "{rendered}");
+        assert!(
+            rendered.contains("no parser source was captured"),
+            "{rendered}"
+        );
+    }
+
+    #[test]
+    fn enclosing_signature_finds_the_nearest_function_header() {
+        let lines = [
+            "fn first(input: &str) -> IResult<&str, &str, ContextError> {",
+            "    tag(\"a\")(input)",
+            "}",
+            "pub(crate) const fn second(input: &str) -> IResult<&str, &str, ContextError> {",
+            "    tag(\"b\")(input)",
+            "}",
+        ];
+        let signature = enclosing_signature(&lines, 4).unwrap();
+        assert_eq!(signature.source_text, lines[3]);
+        assert_eq!(signature.line_number, 4);
+        assert_eq!(signature.end_line_number, None);
+    }
+
+    #[test]
+    fn enclosing_signature_spans_wrapped_signatures() {
+        let lines = [
+            "pub fn weird<'a>(",
+            "    input: &'a str,",
+            ") -> IResult<&'a str, &'a str, ContextError<'a>> {",
+            "    tag(\"x\")(input)",
+            "}",
+        ];
+        let signature = enclosing_signature(&lines, 3).unwrap();
+        assert_eq!(signature.source_text, lines[..3].join("\n"));
+        assert_eq!(signature.line_number, 1);
+        assert_eq!(signature.end_line_number, Some(3));
+    }
+
+    #[test]
+    fn enclosing_signature_requires_a_header() {
+        assert!(enclosing_signature(&["    tag(\"x\")(input)"], 0).is_none());
+        assert!(enclosing_signature(&[], 0).is_none());
+    }
+}
@@ -0,0 +1,524 @@
+//! Captures parser contexts from a build script, for plain `nom` functions that aren't annotated with
+//! `#[annotate_error]`.
+//!
+//! The build script parses the crate's source files and writes a table of every function that
+//! invokes parsers, keyed by its path within the crate:
+//!
+//! ```ignore
+//! // build.rs
+//! fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     nom_plus::build::Capture::new().write()?;
+//!     Ok(())
+//! }
+//! ```
+//!
+//! The table is included once, registered at startup and looked up by the functions that wrap their
+//! parsers with [`with_context`]:
+//!
+//! ```ignore
+//! nom_plus::include_contexts!();
+//!
+//! fn key_value(input: &str) -> IResult<&str, (&str, &str), ContextError> {
+//!     nom_plus::build::with_context("parsers::key_value", |input| {
+//!         let (input, key) = alpha1(input)?;
+//!         let (input, _) = tag("=")(input)?;
+//!         alpha1(input)
+//!     })(input)
+//! }
+//!
+//! fn main() {
+//!     register_parser_contexts();
+//!     // ...
+//! }
+//! ```
+//!
+//! Methods are keyed by the path of their self type without generics, e.g. `grammar::Grammar::header`
+//! for `impl<'g> Grammar<'g>` in `grammar.rs`. Errors point at the source files by their absolute
+//! path, unlike `#[annotate_error]` and `annotate`, which use the path `file!()` reports.
+//!
+//! Unlike the attribute, the build script can't observe which statement failed, so unless the failure
+//! index is set at runtime, the captured statements are shown without marking any of them as failed.
+
+use crate::profile::ProfileKey;
+#[cfg(debug_assertions)]
+use crate::to_tokens::WithCratePath;
+use crate::trace;
+use crate::ContextError;
+#[cfg(debug_assertions)]
+use crate::{ContextFrame, FunctionContext, ParserSourceCapture, SourceCapture};
+use nom::{IResult, InputLength, Parser};
+#[cfg(debug_assertions)]
+use quote::quote;
+use std::path::{Path, PathBuf};
+#[cfg(debug_assertions)]
+use std::sync::{PoisonError, RwLock};
+#[cfg(debug_assertions)]
+use syn::{Block, Expr, ExprClosure, ImplItem, Item, Stmt, Type};
+
+/// The name of the file [`Capture::write`] generates in `OUT_DIR`, see [`include_contexts`](crate::include_contexts).
+pub const CONTEXTS_FILE_NAME: &str = "nom_plus_contexts.rs";
+
+/// Looks up the file and captured context of a function by its path, as generated by [`Capture::write`].
+#[cfg(debug_assertions)]
+pub type ContextTable = fn(&str) -> Option<(&'static str, FunctionContext)>;
+
+#[cfg(debug_assertions)]
+static CONTEXT_TABLE: RwLock<Option<ContextTable>> = RwLock::new(None);
+
+/// Makes the captured contexts available to [`with_context`]. Called by the generated
+/// `register_parser_contexts`.
+#[cfg(debug_assertions)]
+pub fn register_contexts(context_table: ContextTable) {
+    *CONTEXT_TABLE
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = Some(context_table);
+}
+
+/// The file and captured context of the function at `function_path`, e.g. `parsers::key_value` or
+/// `grammar::Grammar::key` for methods, if the table was registered and the function captured.
+#[cfg(debug_assertions)]
+#[must_use]
+pub fn function_context(function_path: &str) -> Option<(&'static str, FunctionContext)> {
+    let context_table = (*CONTEXT_TABLE.read().unwrap_or_else(PoisonError::into_inner))?;
+    context_table(function_path)
+}
+
+/// Attaches the context captured for `function_path` to the errors of `parser`.
+///
+/// Errors that don't carry a context yet receive it directly, while errors that already went through
+/// another captured function record this one as an outer frame of the parser-chain.
+#[allow(clippy::result_large_err)]
+pub fn with_context<'a, Input, Output, ParserType>(
+    function_path: &'static str,
+    mut parser: ParserType,
+) -> impl FnMut(Input) -> IResult<Input, Output, ContextError<'a>>
+where
+    Input: InputLength,
+    ParserType: Parser<Input, Output, ContextError<'a>>,
+{
+    move |input: Input| {
+        let parse = |input: Input| {
+            let result = parser.parse(input);
+            #[cfg(debug_assertions)]
+            let result = result.map_err(|err| {
+                err.map(|mut error| {
+                    attach_context(&mut error, function_path);
+                    error
+                })
+            });
+            result
+        };
+        let describe = || {
+            #[cfg(debug_assertions)]
+            let (file, line) = function_context(function_path)
+                .map_or((None, None), |(file, context)| {
+                    (Some(file), Some(context.signature.line_number))
+                });
+            #[cfg(not(debug_assertions))]
+            let (file, line) = (None, None);
+            trace::Description::function(function_path, file, line)
+        };
+        trace::observe(
+            ProfileKey::Function(function_path),
+            describe,
+            trace::FunctionSpan::parser,
+            input,
+            parse,
+        )
+    }
+}
+
+#[cfg(debug_assertions)]
+fn attach_context(error: &mut ContextError<'_>, function_path: &str) {
+    let Some((file, context)) = function_context(function_path) else {
+        return;
+    };
+    if error.context.parser_contexts.is_none() {
+        error.set_context(context);
+        error.file = Some(file);
+    } else {
+        error.push_frame(ContextFrame::new(context).set_file(file));
+    }
+}
+
+/// Includes the table generated by [`Capture::write`], defining `register_parser_contexts`.
+#[macro_export]
+macro_rules! include_contexts {
+    () => {
+        include!(concat!(env!("OUT_DIR"), "/nom_plus_contexts.rs"));
+    };
+}
+
+/// Which source files a build script captures parser contexts from and where the table is written.
+#[derive(Clone, Debug, Default)]
+pub struct Capture {
+    pub source_dir: Option<PathBuf>,
+    pub output: Option<PathBuf>,
+    pub crate_path: Option<syn::Path>,
+}
+
+impl Capture {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub fn source_dir(&self) -> &Option<PathBuf> {
+        &self.source_dir
+    }
+
+    #[must_use]
+    pub fn output(&self) -> &Option<PathBuf> {
+        &self.output
+    }
+
+    #[must_use]
+    pub fn crate_path(&self) -> &Option<syn::Path> {
+        &self.crate_path
+    }
+
+    /// Sets the directory holding the crate root, `src` in the manifest directory by default.
+    #[must_use]
+    pub fn set_source_dir(mut self, source_dir: impl Into<PathBuf>) -> Self {
+        self.source_dir = Some(source_dir.into());
+        self
+    }
+
+    /// Sets the file the table is written to, [`CONTEXTS_FILE_NAME`] in `OUT_DIR` by default.
+    #[must_use]
+    pub fn set_output(mut self, output: impl Into<PathBuf>) -> Self {
+        self.output = Some(output.into());
+        self
+    }
+
+    /// Sets the path the generated table refers to this crate by, `::nom_plus` by default, for crates
+    /// that rename `nom_plus` or re-export it.
+    #[must_use]
+    pub fn set_crate_path(mut self, crate_path: syn::Path) -> Self {
+        self.crate_path = Some(crate_path);
+        self
+    }
+
+    /// Captures every function of the source directory that invokes parsers and writes the table,
+    /// asking cargo to rerun the build script when any of the source files change.
+    pub fn write(&self) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        let manifest_dir = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR")?);
+        // Joining keeps an absolute source directory as it is
+        let source_dir = manifest_dir.join(self.source_dir.as_deref().unwrap_or(Path::new("src")));
+        let output = match &self.output {
+            Some(output) => output.clone(),
+            None => PathBuf::from(std::env::var("OUT_DIR")?).join(CONTEXTS_FILE_NAME),
+        };
+
+        let mut source_files = Vec::new();
+        collect_source_files(&source_dir, &mut source_files)?;
+        source_files.sort();
+        for source_file in &source_files {
+            println!("cargo:rerun-if-changed={}", source_file.display());
+        }
+
+        #[cfg(debug_assertions)]
+        let table = {
+            let crate_path = self
+                .crate_path
+                .clone()
+                .unwrap_or_else(crate::to_tokens::default_crate_path);
+            capture_table(&source_dir, &source_files, &crate_path)?
+        };
+        // Release builds don't carry the debug-only context types, so the table stays empty
+        #[cfg(not(debug_assertions))]
+        let table = "pub fn register_parser_contexts() {}".to_string();
+
+        std::fs::write(&output, table)?;
+        Ok(output)
+    }
+}
+
+#[cfg(debug_assertions)]
+fn capture_table(
+    source_dir: &Path,
+    source_files: &[PathBuf],
+    crate_path: &syn::Path,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut captured = Vec::new();
+    for source_file in source_files {
+        let file = syn::parse_file(&std::fs::read_to_string(source_file)?)?;
+        // `file!()` is relative to the workspace root, which the build script doesn't know, so the
+        // absolute path is what stays readable for `source_lines`
+        let file_name = source_file.display().to_string();
+        for (function_path, context) in
+            capture_items(&file.items, &module_path(source_dir, source_file))
+        {
+            captured.push((function_path, file_name.clone(), context));
+        }
+    }
+    Ok(table_tokens(&captured, crate_path).to_string())
+}
+
+fn collect_source_files(
+    directory: &Path,
+    source_files: &mut Vec<PathBuf>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_source_files(&path, source_files)?;
+        } else if path.extension().is_some_and(|extension| extension == "rs") {
+            source_files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// The module path of `source_file` within the crate, e.g. `parsers::json` for `src/parsers/json.rs`
+/// or `src/parsers/json/mod.rs` and an empty path for the crate root.
+#[cfg(debug_assertions)]
+fn module_path(source_dir: &Path, source_file: &Path) -> Vec<String> {
+    let relative = source_file.strip_prefix(source_dir).unwrap_or(source_file);
+    let mut segments = relative
+        .with_extension("")
+        .iter()
+        .map(|segment| segment.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    if segments.len() == 1 && matches!(segments[0].as_str(), "lib" | "main") {
+        segments.clear();
+    } else if segments.last().is_some_and(|segment| segment == "mod") {
+        segments.pop();
+    }
+    segments
+}
+
+/// Captures the functions and methods among `items` and the inline modules they contain.
+#[cfg(debug_assertions)]
+fn capture_items(items: &[Item], module_path: &[String]) -> Vec<(String, FunctionContext)> {
+    let function_path = |name: &[&str]| {
+        module_path
+            .iter()
+            .map(String::as_str)
+            .chain(name.iter().copied())
+            .collect::<Vec<_>>()
+            .join("::")
+    };
+    let mut captured = Vec::new();
+    for item in items {
+        match item {
+            Item::Fn(item_fn) => {
+                let context = FunctionContext::default()
+                    .set_signature(item_fn, &mut SourceCapture::default());
+                if let Some(context) = capture_block(context, &item_fn.block) {
+                    captured.push((function_path(&[&item_fn.sig.ident.to_string()]), context));
+                }
+            }
+            Item::Impl(item_impl) => {
+                for impl_item in &item_impl.items {
+                    let ImplItem::Fn(impl_item_fn) = impl_item else {
+                        continue;
+                    };
+                    let context = FunctionContext::default().set_impl_fn_signature(
+                        item_impl,
+                        impl_item_fn,
+                        &mut SourceCapture::default(),
+                    );
+                    let Some(self_type) = type_path(&item_impl.self_ty) else {
+                        continue;
+                    };
+                    if let Some(context) = capture_block(context, &impl_item_fn.block) {
+                        let name = impl_item_fn.sig.ident.to_string();
+                        captured.push((function_path(&[&self_type, &name]), context));
+                    }
+                }
+            }
+            Item::Mod(item_mod) => {
+                if let Some((_, items)) = &item_mod.content {
+                    let mut module_path = module_path.to_vec();
+                    module_path.push(item_mod.ident.to_string());
+                    captured.extend(capture_items(items, &module_path));
+                }
+            }
+            _ => {}
+        }
+    }
+    captured
+}
+
+/// The path of a type without its generic arguments, e.g. `grammar::Grammar` for
+/// `grammar::Grammar<'g>`, which is how methods are looked up. Types other than paths, such as
+/// references or tuples, can't be named that way.
+#[cfg(debug_assertions)]
+fn type_path(ty: &Type) -> Option<String> {
+    match ty {
+        Type::Path(type_path) if type_path.qself.is_none() => Some(
+            type_path
+                .path
+                .segments
+                .iter()
+                .map(|segment| segment.ident.to_string())
+                .collect::<Vec<_>>()
+                .join("::"),
+        ),
+        Type::Group(type_group) => type_path(&type_group.elem),
+        Type::Paren(type_paren) => type_path(&type_paren.elem),
+        _ => None,
+    }
+}
+
+/// Captures the parser statements of a function body, `None` when it doesn't invoke any parsers.
+#[cfg(debug_assertions)]
+fn capture_block(mut context: FunctionContext, block: &Block) -> Option<FunctionContext> {
+    // Functions wrapping their body in `with_context` keep their parsers in the closure
+    if let [Stmt::Expr(expr, None)] = block.stmts.as_slice() {
+        if let Some(expr_closure) = with_context_closure(expr) {
+            let Expr::Block(closure_body) = expr_closure.body.as_ref() else {
+                return None;
+            };
+            let context = context.set_closure(expr_closure, &mut SourceCapture::default());
+            return capture_block(context, &closure_body.block);
+        }
+    }
+
+    for stmt in &block.stmts {
+        let parser_context = match stmt {
+            Stmt::Local(local) => ParserSourceCapture::from_local(local),
+            Stmt::Expr(expr, _) => ParserSourceCapture::from_expr(expr),
+            _ => None,
+        };
+        if let Some(parser_context) = parser_context {
+            context.set_parser_context(parser_context);
+        }
+    }
+    context.parser_contexts.as_ref()?;
+
+    let closing_brace = block.brace_token.span.close();
+    let mut closing_tokens = SourceCapture::default();
+    let _ = closing_tokens
+        .set_source_text("}".to_string())
+        .set_line_number(closing_brace.start().line)
+        .set_start_column(closing_brace.start().column)
+        .set_end_column(closing_brace.end().column)
+        .set_span(closing_brace);
+    Some(context.set_closing_tokens(closing_tokens))
+}
+
+/// The closure passed to `with_context` when `expr` is `with_context("path", |input| { .. })(input)`.
+#[cfg(debug_assertions)]
+fn with_context_closure(expr: &Expr) -> Option<&ExprClosure> {
+    let Expr::Call(invocation) = expr else {
+        return None;
+    };
+    let Expr::Call(with_context) = invocation.func.as_ref() else {
+        return None;
+    };
+    let Expr::Path(function) = with_context.func.as_ref() else {
+        return None;
+    };
+    if function.path.segments.last()?.ident != "with_context" {
+        return None;
+    }
+    with_context
+        .args
+        .iter()
+        .find_map(|argument| match argument {
+            Expr::Closure(expr_closure) => Some(expr_closure),
+            _ => None,
+        })
+}
+
+#[cfg(debug_assertions)]
+fn table_tokens(
+    captured: &[(String, String, FunctionContext)],
+    crate_path: &syn::Path,
+) -> proc_macro2::TokenStream {
+    let arms = captured.iter().map(|(function_path, file, context)| {
+        let context = WithCratePath::new(context, crate_path);
+        quote! {
+            #function_path => ::core::option::Option::Some((#file, #context)),
+        }
+    });
+    quote! {
+        #[cfg(debug_assertions)]
+        fn nom_plus_function_context(
+            function_path: &str,
+        ) -> ::core::option::Option<(&'static str, #crate_path::FunctionContext)> {
+            match function_path {
+                #(#arms)*
+                _ => ::core::option::Option::None,
+            }
+        }
+
+        /// Makes the parser contexts captured by the build script available to
+        /// `nom_plus::build::with_context`.
+        pub fn register_parser_contexts() {
+            #[cfg(debug_assertions)]
+            #crate_path::build::register_contexts(nom_plus_function_context);
+        }
+    }
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn module_path_follows_the_file_layout() {
+        let source_dir = Path::new("src");
+        assert!(module_path(source_dir, Path::new("src/lib.rs")).is_empty());
+        assert!(module_path(source_dir, Path::new("src/main.rs")).is_empty());
+        assert_eq!(
+            module_path(source_dir, Path::new("src/grammar.rs")),
+            ["grammar"]
+        );
+        assert_eq!(
+            module_path(source_dir, Path::new("src/grammar/mod.rs")),
+            ["grammar"]
+        );
+        assert_eq!(
+            module_path(source_dir, Path::new("src/grammar/header.rs")),
+            ["grammar", "header"]
+        );
+        // Only the crate root is called `lib`, a nested module of that name keeps it
+        assert_eq!(
+            module_path(source_dir, Path::new("src/grammar/lib.rs")),
+            ["grammar", "lib"]
+        );
+    }
+
+    #[test]
+    fn type_path_drops_generics() {
+        let type_path = |source: &str| type_path(&syn::parse_str::<Type>(source).unwrap());
+        assert_eq!(type_path("Grammar<'g>").as_deref(), Some("Grammar"));
+        assert_eq!(
+            type_path("grammar::Grammar<'g, T>").as_deref(),
+            Some("grammar::Grammar")
+        );
+        assert_eq!(type_path("&Grammar").as_deref(), None);
+    }
+
+    #[test]
+    fn capture_items_keys_methods_by_their_type_path() {
+        let file = syn::parse_file(
+            r#"
+            impl<'g> Grammar<'g> {
+                fn header(&self, input: &'g str) -> IResult<&'g str, &'g str, ContextError<'g>> {
+                    tag("[")(input)
+                }
+            }
+
+            mod inline {
+                fn key(input: &str) -> IResult<&str, &str, ContextError> {
+                    alpha1(input)
+                }
+            }
+            "#,
+        )
+        .unwrap();
+        let function_paths = capture_items(&file.items, &["grammar".to_string()])
+            .into_iter()
+            .map(|(function_path, _)| function_path)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            function_paths,
+            ["grammar::Grammar::header", "grammar::inline::key"]
+        );
+    }
+}
@@ -0,0 +1,435 @@
+use crate::theme::Theme;
+use std::{
+    borrow::Cow,
+    sync::{PoisonError, RwLock},
+};
+
+static RENDER_CONFIG: RwLock<RenderConfig> = RwLock::new(RenderConfig::new());
+
+/// Process-wide settings used when a `ContextError` is rendered through `Debug` or `Display`.
+///
+/// Formatting traits don't take parameters, so the configuration is stored globally and can be
+/// changed at any point at runtime with [`set_render_config`] or the individual setters below.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RenderConfig {
+    pub theme: Theme,
+    /// How inputs are shortened before being rendered, `None` renders them in full.
+    pub truncation: Option<Truncation>,
+    /// How far up the parser-chain inputs are rendered.
+    pub input_depth: InputDepth,
+    /// How many captured source lines are shown above and below the failing statement, `None` shows
+    /// every captured statement of the function.
+    pub context_lines: Option<usize>,
+    /// Whether the function's closing tokens are rendered after the captured statements.
+    pub show_closing_tokens: bool,
+    /// How many lines of the source file on disk are shown above and below the captured statements,
+    /// `None` only shows the captured source. The file is left alone when it changed since compilation.
+    pub source_lines: Option<usize>,
+}
+
+impl RenderConfig {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            theme: Theme::standard(),
+            truncation: Some(Truncation::new()),
+            input_depth: InputDepth::DEFAULT,
+            context_lines: None,
+            show_closing_tokens: true,
+            source_lines: None,
+        }
+    }
+
+    #[must_use]
+    pub fn theme(&self) -> &Theme {
+        &self.theme
+    }
+
+    #[must_use]
+    pub fn truncation(&self) -> &Option<Truncation> {
+        &self.truncation
+    }
+
+    #[must_use]
+    pub fn input_depth(&self) -> &InputDepth {
+        &self.input_depth
+    }
+
+    #[must_use]
+    pub fn context_lines(&self) -> &Option<usize> {
+        &self.context_lines
+    }
+
+    #[must_use]
+    pub fn show_closing_tokens(&self) -> bool {
+        self.show_closing_tokens
+    }
+
+    #[must_use]
+    pub fn source_lines(&self) -> &Option<usize> {
+        &self.source_lines
+    }
+
+    #[must_use]
+    pub fn set_theme(mut self, theme: impl Into<Theme>) -> Self {
+        self.theme = theme.into();
+        self
+    }
+
+    #[must_use]
+    pub fn set_truncation(mut self, truncation: Option<Truncation>) -> Self {
+        self.truncation = truncation;
+        self
+    }
+
+    #[must_use]
+    pub fn set_input_depth(mut self, input_depth: InputDepth) -> Self {
+        self.input_depth = input_depth;
+        self
+    }
+
+    #[must_use]
+    pub fn set_context_lines(mut self, context_lines: Option<usize>) -> Self {
+        self.context_lines = context_lines;
+        self
+    }
+
+    #[must_use]
+    pub fn set_show_closing_tokens(mut self, show_closing_tokens: bool) -> Self {
+        self.show_closing_tokens = show_closing_tokens;
+        self
+    }
+
+    #[must_use]
+    pub fn set_source_lines(mut self, source_lines: Option<usize>) -> Self {
+        self.source_lines = source_lines;
+        self
+    }
+
+    /// Shortens `input` according to the configured truncation, if any.
+    #[must_use]
+    pub fn truncate<'s>(&self, input: &'s str) -> Cow<'s, str> {
+        self.truncation
+            .as_ref()
+            .map_or(Cow::Borrowed(input), |truncation| truncation.apply(input))
+    }
+
+    /// Renders `input` as an escaped string literal, shortened according to the configured truncation.
+    #[must_use]
+    pub fn quote(&self, input: &str) -> String {
+        self.truncation.as_ref().map_or_else(
+            || format!("{input:?}"),
+            |truncation| truncation.apply_quoted(input),
+        )
+    }
+}
+
+/// Which links of the parser-chain have their input rendered, counted from the link the error occurred in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputDepth {
+    /// Only the input of the function the error occurred in.
+    FailingLink,
+    /// The inputs of the `N` links nearest to the error, including the failing link itself.
+    Nearest(usize),
+    /// The input at every link of the chain.
+    All,
+}
+
+impl InputDepth {
+    pub const DEFAULT: Self = Self::Nearest(3);
+
+    /// Whether the input of the link `distance` links up the chain from the failing link is rendered.
+    #[must_use]
+    pub const fn shows(&self, distance: usize) -> bool {
+        match self {
+            Self::FailingLink => distance == 0,
+            Self::Nearest(links) => distance < *links,
+            Self::All => true,
+        }
+    }
+}
+
+impl Default for InputDepth {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Shortens long inputs to a window of their first and, optionally, last characters.
+///
+/// With the defaults, an input longer than `max_width` characters is rendered as its head followed by
+/// an ellipsis and the size of what was left out, e.g. `hello wor… (4.2 MB more)`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Truncation {
+    /// The number of characters an input may have before it is truncated.
+    pub max_width: usize,
+    /// How many of the `max_width` characters are taken from the end of the input instead of the start.
+    pub tail_width: usize,
+}
+
+impl Truncation {
+    pub const DEFAULT_MAX_WIDTH: usize = 64;
+
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            max_width: Self::DEFAULT_MAX_WIDTH,
+            tail_width: 0,
+        }
+    }
+
+    #[must_use]
+    pub const fn set_max_width(mut self, max_width: usize) -> Self {
+        self.max_width = max_width;
+        self
+    }
+
+    #[must_use]
+    pub const fn set_tail_width(mut self, tail_width: usize) -> Self {
+        self.tail_width = tail_width;
+        self
+    }
+
+    #[must_use]
+    pub fn apply<'s>(&self, input: &'s str) -> Cow<'s, str> {
+        match self.window(input) {
+            Some(window) => Cow::Owned(format!(
+                "{}…{} ({} more)",
+                window.head,
+                window.tail,
+                format_size(window.omitted_bytes)
+            )),
+            None => Cow::Borrowed(input),
+        }
+    }
+
+    /// Like [`Truncation::apply`] but renders the kept text as an escaped string literal with the
+    /// omitted size outside the quotes, e.g. `"hello wor…" (4.2 MB more)`.
+    #[must_use]
+    pub fn apply_quoted(&self, input: &str) -> String {
+        match self.window(input) {
+            Some(window) => format!(
+                "\"{}…{}\" ({} more)",
+                window.head.escape_debug(),
+                window.tail.escape_debug(),
+                format_size(window.omitted_bytes)
+            ),
+            None => format!("{input:?}"),
+        }
+    }
+
+    /// Splits `input` into the head and tail that are kept, or `None` when it fits within `max_width`.
+    #[must_use]
+    pub fn window<'s>(&self, input: &'s str) -> Option<TruncatedWindow<'s>> {
+        input.char_indices().nth(self.max_width)?;
+
+        let tail_width = self.tail_width.min(self.max_width);
+        let head_end = input
+            .char_indices()
+            .nth(self.max_width - tail_width)
+            .map_or(input.len(), |(index, _)| index);
+        let tail_start = if tail_width == 0 {
+            input.len()
+        } else {
+            input
+                .char_indices()
+                .rev()
+                .nth(tail_width - 1)
+                .map_or(head_end, |(index, _)| index.max(head_end))
+        };
+
+        Some(TruncatedWindow {
+            head: &input[..head_end],
+            tail: &input[tail_start..],
+            omitted_bytes: tail_start - head_end,
+        })
+    }
+}
+
+/// The parts of an input kept by a [`Truncation`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TruncatedWindow<'s> {
+    pub head: &'s str,
+    pub tail: &'s str,
+    pub omitted_bytes: usize,
+}
+
+impl Default for Truncation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Formats a byte count the way file sizes are usually displayed, e.g. `512 B` or `4.2 MB`.
+fn format_size(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1000 {
+        return format!("{bytes} B");
+    }
+    #[allow(clippy::cast_precision_loss)]
+    let mut size = bytes as f64 / 1000.0;
+    let mut unit = UNITS[0];
+    for next_unit in &UNITS[1..] {
+        // Sizes that round up to 1000.0 at one decimal read better in the next unit
+        if size < 999.95 {
+            break;
+        }
+        size /= 1000.0;
+        unit = next_unit;
+    }
+    format!("{size:.1} {unit}")
+}
+
+impl Default for RenderConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns a snapshot of the current render configuration.
+#[must_use]
+pub fn render_config() -> RenderConfig {
+    RENDER_CONFIG
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+pub fn set_render_config(config: RenderConfig) {
+    *RENDER_CONFIG
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = config;
+}
+
+/// Selects the theme used for every subsequently rendered error, e.g. `set_theme(ThemePreset::HighContrast)`.
+pub fn set_theme(theme: impl Into<Theme>) {
+    RENDER_CONFIG
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .theme = theme.into();
+}
+
+/// Sets how inputs are truncated in subsequently rendered errors, `None` renders them in full.
+pub fn set_truncation(truncation: Option<Truncation>) {
+    RENDER_CONFIG
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .truncation = truncation;
+}
+
+/// Sets how far up the parser-chain inputs are rendered in subsequently rendered errors.
+pub fn set_input_depth(input_depth: InputDepth) {
+    RENDER_CONFIG
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .input_depth = input_depth;
+}
+
+/// Sets how many captured source lines are shown around the failing statement, `None` shows them all.
+pub fn set_context_lines(context_lines: Option<usize>) {
+    RENDER_CONFIG
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .context_lines = context_lines;
+}
+
+/// Sets whether the closing tokens of the function are rendered after its statements.
+pub fn set_show_closing_tokens(show_closing_tokens: bool) {
+    RENDER_CONFIG
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .show_closing_tokens = show_closing_tokens;
+}
+
+/// Sets how many lines of the source file on disk are shown around the captured statements, `None`
+/// only shows the captured source.
+pub fn set_source_lines(source_lines: Option<usize>) {
+    RENDER_CONFIG
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .source_lines = source_lines;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_keeps_inputs_within_max_width() {
+        let truncation = Truncation::new().set_max_width(5);
+        assert_eq!(truncation.window("hello"), None);
+        assert_eq!(truncation.window(""), None);
+        assert_eq!(truncation.apply("hello"), "hello");
+    }
+
+    #[test]
+    fn window_keeps_the_head() {
+        let window = Truncation::new()
+            .set_max_width(5)
+            .window("hello world")
+            .unwrap();
+        assert_eq!(
+            window,
+            TruncatedWindow {
+                head: "hello",
+                tail: "",
+                omitted_bytes: 6,
+            }
+        );
+    }
+
+    #[test]
+    fn window_splits_between_head_and_tail() {
+        let window = Truncation::new()
+            .set_max_width(6)
+            .set_tail_width(2)
+            .window("hello world")
+            .unwrap();
+        assert_eq!((window.head, window.tail), ("hell", "ld"));
+        assert_eq!(window.omitted_bytes, 5);
+    }
+
+    #[test]
+    fn window_clamps_the_tail_to_max_width() {
+        let window = Truncation::new()
+            .set_max_width(3)
+            .set_tail_width(10)
+            .window("hello world")
+            .unwrap();
+        assert_eq!((window.head, window.tail), ("", "rld"));
+        assert_eq!(window.omitted_bytes, 8);
+    }
+
+    #[test]
+    fn window_counts_characters_and_omits_bytes() {
+        let window = Truncation::new()
+            .set_max_width(3)
+            .set_tail_width(1)
+            .window("héllo wörld")
+            .unwrap();
+        assert_eq!((window.head, window.tail), ("hé", "d"));
+        assert_eq!(window.omitted_bytes, "llo wörl".len());
+    }
+
+    #[test]
+    fn apply_renders_the_omitted_size() {
+        let truncation = Truncation::new().set_max_width(5).set_tail_width(1);
+        assert_eq!(truncation.apply("hello world"), "hell…d (6 B more)");
+        assert_eq!(
+            truncation.apply_quoted("hell\"o world"),
+            "\"hell…d\" (7 B more)"
+        );
+        assert_eq!(truncation.apply_quoted("a\"b"), "\"a\\\"b\"");
+    }
+
+    #[test]
+    fn format_size_uses_decimal_units() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(999), "999 B");
+        assert_eq!(format_size(1000), "1.0 KB");
+        assert_eq!(format_size(4_200_000), "4.2 MB");
+        assert_eq!(format_size(999_949), "999.9 KB");
+        assert_eq!(format_size(999_999), "1.0 MB");
+        assert_eq!(format_size(3_000_000_000_000_000), "3000.0 TB");
+    }
+}
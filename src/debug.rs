@@ -1,11 +1,11 @@
 use crate::prelude::*;
-use std::{
-    env,
-    fmt::{Debug, Formatter},
-};
+use std::fmt::{Debug, Formatter};
 
 use annotate_snippets::{Level, Renderer, Snippet};
-use std::path::PathBuf;
+
+/// How far the text after `    pattern: ` in the Expected footer is indented.
+#[cfg(debug_assertions)]
+const PATTERN_INDENT: usize = "    pattern: ".len();
 
 impl<'a> Debug for ContextError<'a> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -20,62 +20,439 @@ impl<'a> Debug for ContextError<'a> {
     }
 }
 impl<'a> ContextError<'a> {
-    #[allow(clippy::unnecessary_wraps, clippy::range_plus_one)]
+    #[allow(clippy::unnecessary_wraps)]
     #[cfg(debug_assertions)]
     pub fn fmt_annotation(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let current_dir = env::current_dir().unwrap_or_else(|_| PathBuf::from("unknown"));
-
-        let context = self.context();
-        dbg!("CONTEXT!", &context);
-        let signature = &context.signature;
-        let signature_text = &signature.source_text;
-        let signature_line_number = signature.line_number;
-        let parser_contexts = context.parser_contexts();
-
-        let message = match self.combine_parser_sources() {
-            Ok(source_capture) => {
-                let parser_context_span = source_capture.span_length.unwrap();
-                let parser_context_text = source_capture.source_text.into_boxed_str();
-                let parser_context_line_number = source_capture.line_number;
-                Level::Error
-                    .title("ContextError")
-                    .snippet(
-                        Snippet::source(signature_text.as_str())
-                            .origin(self.file.unwrap_or_default())
-                            .line_start(signature_line_number),
-                    )
-                    .snippet(
-                        Snippet::source(Box::leak(parser_context_text))
-                            .line_start(parser_context_line_number)
-                            .annotation(
-                                Level::Error
-                                    .span(
-                                        source_capture.start_column.unwrap()
-                                            ..parser_context_span + 1, // This is a hack. when trying to capture bindings such as (remaining,captured) = some_parser(input), we have to manually add the space after the eq_token and that leads to the span being 1 short. this may cause future problems with being outside of the SourceAnnotation range buffer
-                                    )
-                                    .label("error occurred here"),
-                            ), // .annotation(
-                               // Level::Info.span(0..4).label(self.input.unwrap_or_default()),
-                               // ),
-                    )
-                    .footer(
-                        Level::Info.title(self.input.unwrap_or_default()), // .span(0..4)
-                    )
+        let config = render_config();
+        let theme = config.theme;
+
+        // The chain is rendered outermost-to-innermost, ending with the function the error occurred in
+        let outer_frames = self.chain.iter().rev().collect::<Vec<_>>();
+        let callee_names = outer_frames
+            .iter()
+            .skip(1)
+            .map(|frame| frame.context.qualified_name())
+            .chain(std::iter::once(self.context.qualified_name()))
+            .map(|name| name.unwrap_or_else(|| "annotated function".to_string()))
+            .collect::<Vec<_>>();
+        // A frame whose failing statement wasn't recorded, e.g. one captured by a build script, can't
+        // tell which of its statements made the call
+        let call_sources = outer_frames
+            .iter()
+            .map(|frame| {
+                frame.context.recorded_failure_index()?;
+                frame.context.combine_parser_sources().ok()
+            })
+            .collect::<Vec<_>>();
+        let call_labels = callee_names
+            .iter()
+            .map(|name| format!("`{name}` called here"))
+            .collect::<Vec<_>>();
+        let frame_count = outer_frames.len();
+        let frame_inputs = outer_frames
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| config.input_depth.shows(frame_count - index))
+            // Frames attached without their input, e.g. by `with_context`, have nothing to show
+            .filter_map(|(_, frame)| {
+                let input = frame.input?;
+                Some(format!(
+                    "input at {}: {}",
+                    theme.paint_parser_name(
+                        frame
+                            .context
+                            .qualified_name()
+                            .unwrap_or_else(|| "?".to_string())
+                    ),
+                    theme.paint_found(config.quote(input))
+                ))
+            })
+            .collect::<Vec<_>>();
+
+        let failing_parser = self
+            .context
+            .parser_contexts
+            .as_ref()
+            .zip(self.context.recorded_failure_index())
+            .and_then(|(parser_contexts, failure_index)| parser_contexts.get(failure_index));
+        let found = format!(
+            "Found:\n    {}: {}",
+            failing_parser
+                .and_then(ParserSourceCapture::input_name)
+                .unwrap_or("input"),
+            theme.paint_found(config.quote(self.input.unwrap_or_default()))
+        );
+        // When a nested parser inside the failing combinator is known to have failed, it is the one
+        // whose expectation is reported
+        let failing_nested_parser = self.context.failing_nested_parser();
+        let failing_nested_context = self.context.failing_nested_parser_context();
+        let expected = self
+            .expectation
+            .map(|expectation| theme.paint_expected(expectation).to_string())
+            .or_else(|| self.expected(failing_parser, failing_nested_parser.as_ref()));
+        // Kept apart from `found` so that hiding the failing input doesn't hide what was expected
+        let expected = expected.map(|expected| format!("Expected:\n    {expected}"));
+        let error_label = self.label.unwrap_or("error occurred here");
+
+        let closing_tokens = self
+            .context
+            .closing_tokens
+            .as_ref()
+            .filter(|_| config.show_closing_tokens)
+            .map(|closing_tokens| {
+                let indent = " ".repeat(closing_tokens.start_column.unwrap_or_default());
+                (
+                    closing_tokens,
+                    format!("{indent}{}", closing_tokens.source_text),
+                )
+            });
+
+        let mut snippets = Vec::new();
+        for ((frame, call_source), call_label) in outer_frames
+            .iter()
+            .zip(call_sources.iter())
+            .zip(call_labels.iter())
+        {
+            push_signature_snippets(&mut snippets, &frame.context, frame.file);
+            if let Some(call_source) = call_source {
+                snippets.push(
+                    Snippet::source(&call_source.source_text)
+                        .line_start(call_source.line_number)
+                        .annotation(
+                            Level::Note
+                                .span(annotation_range(call_source))
+                                .label(call_label),
+                        ),
+                );
             }
+        }
 
-            _e => {
-                Level::Error.title("HANDLE ALTERNATIVE CASE ERROR FOR PARSERS ON DIFFERENT LINES")
+        push_signature_snippets(&mut snippets, &self.context, self.file);
+        let parser_statements =
+            self.combine_all_parser_sources()
+                .map(|statements| match config.context_lines {
+                    Some(context_lines) => statements.window(context_lines),
+                    None => statements,
+                });
+        // Extending the view with the file on disk is best effort: an unreadable file silently keeps
+        // the captured source, while a file that changed since compilation is pointed out
+        let mut source_changed = false;
+        let parser_statements = parser_statements.map(|statements| {
+            let (Some(source_lines), Some(file)) = (config.source_lines, self.file) else {
+                return statements;
+            };
+            let Ok(file_source) = std::fs::read_to_string(file) else {
+                return statements;
+            };
+            let header_end = std::iter::once(&self.context.signature)
+                .chain(self.context.closure.as_ref())
+                .map(|header| header.line_number + header.source_text.matches('\n').count())
+                .max()
+                .unwrap_or_default();
+            let closing_line = closing_tokens
+                .as_ref()
+                .map_or(usize::MAX, |(closing_tokens, _)| closing_tokens.line_number);
+            statements
+                .with_file_source(
+                    &file_source,
+                    source_lines,
+                    header_end + 1..=closing_line.saturating_sub(1),
+                )
+                .unwrap_or_else(|mismatch| {
+                    // Without a location there's nothing to compare the file against
+                    source_changed = mismatch == FileSourceMismatch::Changed;
+                    statements
+                })
+        });
+        // Windowing may drop leading statements, so bindings are matched to the statements shown
+        // by counting back from the failing statement
+        let binding_labels = self
+            .captured_bindings
+            .iter()
+            .map(|binding| {
+                (
+                    self.context.failure_index()
+                        - binding.statement_index.min(self.context.failure_index()),
+                    format!(
+                        "parsed successfully, consumed {} and captured {}",
+                        config.quote(binding.consumed),
+                        config.truncate(&binding.captured)
+                    ),
+                )
+            })
+            .collect::<Vec<_>>();
+        let failure_known = self.context.recorded_failure_index().is_some();
+        if let Ok(statements) = &parser_statements {
+            let failure_index = statements.failure_index;
+            // Statements after the failing one never ran, so they are shown without annotations, and
+            // none are annotated when it isn't known which one failed
+            let mut annotations = Vec::new();
+            for (index, spans) in statements
+                .statement_spans
+                .iter()
+                .enumerate()
+                .filter(|_| failure_known)
+            {
+                if index < failure_index {
+                    let label = binding_labels
+                        .iter()
+                        .find(|(distance, _)| *distance == failure_index - index)
+                        .map_or("parsed successfully", |(_, label)| label.as_str());
+                    annotations.push(Level::Note.span(spans.statement.clone()).label(label));
+                } else if index == failure_index {
+                    let nested_span = failing_nested_parser
+                        .as_ref()
+                        .and_then(|nested_parser| statements.span_of(nested_parser, index));
+                    if let Some(nested_span) = nested_span {
+                        // The combinator is underlined as context for the nested parser that failed
+                        annotations.push(
+                            Level::Info
+                                .span(spans.statement.clone())
+                                .label("in this combinator"),
+                        );
+                        annotations.push(Level::Error.span(nested_span).label(error_label));
+                        if let Some(pattern) = failing_nested_context.and_then(|nested_context| {
+                            statements.span_of(&nested_context.pattern, index)
+                        }) {
+                            annotations.push(Level::Info.span(pattern).label("expected"));
+                        }
+                    } else {
+                        annotations.push(
+                            Level::Error
+                                .span(spans.statement.clone())
+                                .label(error_label),
+                        );
+                        if let Some(pattern) = &spans.pattern {
+                            annotations.push(Level::Info.span(pattern.clone()).label("expected"));
+                        }
+                    }
+                    if let Some(input) = &spans.input {
+                        annotations.push(Level::Info.span(input.clone()).label("found"));
+                    }
+                }
             }
-        };
-        // dbg!("SOURCE CAPTURE", &source_capture);
+            snippets.push(
+                // A parser expression spanning several lines is rendered as a multi-line
+                // annotation by `annotate_snippets` since the range crosses the newlines
+                Snippet::source(&statements.source.source_text)
+                    .line_start(statements.source.line_number)
+                    .annotations(annotations),
+            );
+        }
+
+        if let Some((closing_tokens, closing_text)) = &closing_tokens {
+            // Rendered as its own snippet so the lines between the last statement shown and the end
+            // of the function are elided
+            snippets.push(
+                Snippet::source(closing_text.as_str()).line_start(closing_tokens.line_number),
+            );
+        }
+
+        // Parsers created by factories also point at the call site that created them
+        let instantiation_notes = outer_frames
+            .iter()
+            .map(|frame| &frame.context)
+            .chain(std::iter::once(&self.context))
+            .filter_map(|context| {
+                let location = context.instantiated_at?;
+                Some(format!(
+                    "parser returned by {} was created at {}:{}:{}",
+                    theme.paint_parser_name(
+                        context.qualified_name().unwrap_or_else(|| "?".to_string())
+                    ),
+                    location.file(),
+                    location.line(),
+                    location.column()
+                ))
+            })
+            .collect::<Vec<_>>();
+
+        let source_changed_note = format!(
+            "{} changed since it was compiled, showing the captured source instead",
+            self.file.unwrap_or("the source file")
+        );
+        let mut footers = Vec::new();
+        if parser_statements.is_err() {
+            footers.push(Level::Note.title("no parser source was captured for this error"));
+        }
+        if parser_statements.is_ok() && !failure_known {
+            footers.push(Level::Note.title("it wasn't recorded which of these statements failed"));
+        }
+        if source_changed {
+            footers.push(Level::Note.title(&source_changed_note));
+        }
+        footers.extend(
+            instantiation_notes
+                .iter()
+                .map(|instantiation_note| Level::Note.title(instantiation_note)),
+        );
+        footers.extend(frame_inputs.iter().map(|input| Level::Note.title(input)));
+        if config.input_depth.shows(0) {
+            footers.push(Level::Info.title(&found));
+        }
+        if let Some(expected) = &expected {
+            footers.push(Level::Info.title(expected));
+        }
+
+        if let Some(help) = self.help {
+            footers.push(Level::Help.title(help));
+        }
 
-        // dbg!("SOURCE?", &source_capture);
-        // dbg!("MESSAGE", &message);
+        let title = self.parser_name.map_or_else(
+            || "ContextError".to_string(),
+            |parser_name| format!("ContextError in {parser_name}"),
+        );
+        let message = Level::Error
+            .title(&title)
+            .snippets(snippets)
+            .footers(footers);
 
-        let style = anstyle::Style::new().fg_color(Some(anstyle::AnsiColor::BrightRed.into()));
-        let renderer = Renderer::styled().line_no(style);
+        // The styled renderer emphasizes titles on its own, which a plain theme mustn't
+        let renderer = if theme.is_plain() {
+            Renderer::plain()
+        } else {
+            Renderer::styled()
+                .error(theme.error)
+                .info(theme.info)
+                .note(theme.note)
+                .help(theme.help)
+                .line_no(theme.line_number)
+        };
         let rendered_output = renderer.render(message);
         let _ = writeln!(f, "{rendered_output}").is_ok();
         Ok(())
     }
+
+    /// The expectation derived from the captured source: the pattern of the failing parser or of the
+    /// nested parser that failed inside it, falling back to the parser's name.
+    #[cfg(debug_assertions)]
+    fn expected(
+        &self,
+        failing_parser: Option<&ParserSourceCapture>,
+        failing_nested_parser: Option<&SourceCapture>,
+    ) -> Option<String> {
+        let config = render_config();
+        let theme = config.theme;
+        let failing_nested_context = self.context.failing_nested_parser_context();
+        failing_nested_context
+            .or(failing_nested_parser
+                .is_none()
+                .then_some(failing_parser)
+                .flatten())
+            .and_then(|parser| {
+                parser
+                    .pattern_text()
+                    .map(|pattern| match &self.pattern_value {
+                        // Literal patterns evaluate to their own source text, so the value is only
+                        // shown for constants and expressions
+                        Some(value) if value != pattern => format!(
+                            "pattern: {}\n    value: {}",
+                            theme.paint_expected(align_continuation_lines(pattern, PATTERN_INDENT)),
+                            theme.paint_expected(config.truncate(value))
+                        ),
+                        _ => format!(
+                            "pattern: {}",
+                            theme.paint_expected(align_continuation_lines(pattern, PATTERN_INDENT))
+                        ),
+                    })
+                    .or_else(|| {
+                        Some(parser.ident.source_text.trim())
+                            .filter(|ident| !ident.is_empty())
+                            .map(|ident| format!("parser: {}", theme.paint_parser_name(ident)))
+                    })
+            })
+            .or_else(|| {
+                failing_nested_parser.map(|nested_parser| {
+                    format!(
+                        "parser: {}",
+                        theme.paint_parser_name(nested_parser.source_text.trim())
+                    )
+                })
+            })
+    }
+}
+
+/// Pushes the function's signature, preceded by the header of the `impl` or `trait` block it is defined
+/// in for methods and followed by the header of the closure the parsers live in for parser factories,
+/// with the first snippet pointing at `file`.
+#[cfg(debug_assertions)]
+fn push_signature_snippets<'s>(
+    snippets: &mut Vec<Snippet<'s>>,
+    context: &'s FunctionContext,
+    file: Option<&'static str>,
+) {
+    // Errors without a captured context, e.g. from an `Annotated` parser outside of any annotated
+    // function, have no headers to show
+    let headers = context
+        .enclosing_item
+        .iter()
+        .chain([&context.signature])
+        .chain(context.closure.iter())
+        .filter(|header| !header.source_text.is_empty());
+    for (index, header) in headers.enumerate() {
+        let snippet = Snippet::source(header.source_text.as_str()).line_start(header.line_number);
+        snippets.push(if index == 0 {
+            snippet.origin(file.unwrap_or_default())
+        } else {
+            snippet
+        });
+    }
+}
+
+/// The range `merge_source` leaves covering the merged text of a combined `SourceCapture`.
+#[cfg(debug_assertions)]
+fn annotation_range(source_capture: &SourceCapture) -> std::ops::Range<usize> {
+    source_capture.start_column.unwrap_or_default()
+        ..source_capture
+            .span_length
+            .unwrap_or(source_capture.source_text.len())
+}
+
+/// Lines `text` up below its first line when it spans several lines, e.g. a pattern whose arguments
+/// were wrapped by rustfmt, by indenting the continuation lines by `indent` columns while keeping
+/// their indentation relative to each other.
+#[cfg(debug_assertions)]
+fn align_continuation_lines(text: &str, indent: usize) -> String {
+    let mut lines = text.lines();
+    let first_line = lines.next().unwrap_or_default().trim_start();
+    let continuation_lines = lines.collect::<Vec<_>>();
+    let common_indent = continuation_lines
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or_default();
+    let mut aligned = first_line.to_string();
+    for line in continuation_lines {
+        aligned.push('\n');
+        if !line.trim().is_empty() {
+            aligned.push_str(&" ".repeat(indent));
+            aligned.push_str(&line[common_indent..]);
+        }
+    }
+    aligned
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn align_continuation_lines_keeps_single_lines() {
+        assert_eq!(align_continuation_lines(r#"tag("=")"#, 4), r#"tag("=")"#);
+    }
+
+    #[test]
+    fn align_continuation_lines_indents_relative_to_the_first_line() {
+        let pattern = "tag(\"(\"),\n        alpha1,\n            digit1,\n        tag(\")\"),";
+        assert_eq!(
+            align_continuation_lines(pattern, 4),
+            "tag(\"(\"),\n    alpha1,\n        digit1,\n    tag(\")\"),"
+        );
+    }
+
+    #[test]
+    fn align_continuation_lines_skips_blank_lines() {
+        assert_eq!(align_continuation_lines("a,\n\n      b", 2), "a,\n\n  b");
+    }
 }
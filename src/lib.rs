@@ -1,20 +1,63 @@
+pub mod annotate;
+pub mod build;
+pub mod config;
 pub mod debug;
+pub mod profile;
+pub mod recovery;
+pub mod theme;
 pub mod to_tokens;
+pub mod trace;
+#[cfg(feature = "tracing")]
+#[doc(hidden)]
+pub use tracing as __tracing;
+
 use nom::{IResult, Parser};
-use proc_macro2::{Span, TokenStream};
+#[cfg(debug_assertions)]
+use proc_macro2::{Delimiter, LineColumn, Span, TokenStream, TokenTree};
+#[cfg(debug_assertions)]
 use quote::ToTokens;
 use std::fmt::{Debug, Display, Formatter};
-use syn::{spanned::Spanned, ItemFn};
+#[cfg(debug_assertions)]
+use std::{
+    ops::{Range, RangeInclusive},
+    panic::Location,
+};
+#[cfg(debug_assertions)]
+use syn::{
+    punctuated::Punctuated,
+    spanned::Spanned,
+    token::{Brace, Comma, Paren},
+    Block, Expr, ExprClosure, ExprPath, File, ImplItemFn, Item, ItemFn, ItemImpl, ItemTrait, Local,
+    Signature, TraitItemFn, Visibility,
+};
 
 #[allow(unused_imports)]
 pub mod prelude {
-    pub use super::ContextError;
+    pub use super::{
+        annotate::{annotate, Annotated},
+        config::{
+            render_config, set_context_lines, set_input_depth, set_render_config,
+            set_show_closing_tokens, set_source_lines, set_theme, set_truncation, InputDepth,
+            RenderConfig, Truncation,
+        },
+        profile::{profile_report, profiling, reset_profile, set_profiling, ProfileReport},
+        recovery::{insert_placeholder, skip_line, skip_to, Diagnostics},
+        theme::{Theme, ThemePreset},
+        trace::{set_tracing, trace, tracing},
+        ContextError,
+    };
     #[cfg(debug_assertions)]
     pub use super::{
-        debug::*, map_parser_err, FunctionContext, ParserSourceCapture, SourceCapture,
+        debug::*, map_parser_err, CapturedBinding, ContextFrame, FileSourceMismatch,
+        FunctionContext, ParserSourceCapture, ParserStatementsCapture, SourceCapture,
     };
 }
 
+/// The error type of annotated parsers.
+///
+/// Debug builds carry the whole captured context by value, which trips `clippy::result_large_err`
+/// for the combinators returning it. Those allow the lint individually until the planned
+/// memory-footprint refactor shrinks the error.
 #[derive(Clone, Default)]
 pub struct ContextError<'a> {
     pub message: Option<&'a str>,
@@ -23,6 +66,27 @@ pub struct ContextError<'a> {
     #[cfg(debug_assertions)]
     pub file: Option<&'static str>,
     pub input: Option<&'a str>,
+    #[cfg(debug_assertions)]
+    pub chain: Vec<ContextFrame<'a>>,
+    /// The `Debug` rendering of the failing parser's pattern as evaluated at runtime, so that patterns
+    /// given as constants or expressions, e.g. `tag(KEYWORD)`, can be shown alongside their source.
+    #[cfg(debug_assertions)]
+    pub pattern_value: Option<String>,
+    /// What the statements that succeeded before the failing one consumed and produced.
+    #[cfg(debug_assertions)]
+    pub captured_bindings: Vec<CapturedBinding<'a>>,
+    /// What the failing parser is called in the grammar, e.g. `header`, see [`annotate::Annotated`].
+    #[cfg(debug_assertions)]
+    pub parser_name: Option<&'a str>,
+    /// A description of what the failing parser expected, e.g. `a section header like [name]`.
+    #[cfg(debug_assertions)]
+    pub expectation: Option<&'a str>,
+    /// The label of the failing span, replacing the default `error occurred here`.
+    #[cfg(debug_assertions)]
+    pub label: Option<&'a str>,
+    /// A hint on how to fix the input, rendered as a help footer.
+    #[cfg(debug_assertions)]
+    pub help: Option<&'a str>,
 }
 
 impl<'a> ContextError<'a> {
@@ -35,6 +99,20 @@ impl<'a> ContextError<'a> {
             #[cfg(debug_assertions)]
             file: None,
             input: None,
+            #[cfg(debug_assertions)]
+            chain: Vec::new(),
+            #[cfg(debug_assertions)]
+            pattern_value: None,
+            #[cfg(debug_assertions)]
+            captured_bindings: Vec::new(),
+            #[cfg(debug_assertions)]
+            parser_name: None,
+            #[cfg(debug_assertions)]
+            expectation: None,
+            #[cfg(debug_assertions)]
+            label: None,
+            #[cfg(debug_assertions)]
+            help: None,
         }
     }
 
@@ -68,32 +146,105 @@ impl<'a> ContextError<'a> {
     }
     #[cfg(debug_assertions)]
     pub fn combine_parser_sources(&self) -> Result<SourceCapture, Box<dyn std::error::Error>> {
-        self.context
-            .parser_contexts
-            .as_ref()
-            .ok_or_else(|| "No parser contexts available".to_string())?
-            .iter()
-            .map(|parser_source_capture| {
-                dbg!(&parser_source_capture.binding_pattern);
-                let line_number = parser_source_capture.ident.line_number;
-                let mut source_capture_state = SourceCapture::default();
-                if let Some(binding_pattern) = &parser_source_capture.binding_pattern {
-                    source_capture_state
-                        .merge_source(binding_pattern, line_number)
-                        .merge_source(&parser_source_capture.ident, line_number)
-                        .merge_source(&parser_source_capture.pattern, line_number)
-                        .merge_source(&parser_source_capture.input, line_number);
-                } else {
-                    source_capture_state
-                        .merge_source(&parser_source_capture.ident, line_number)
-                        .merge_source(&parser_source_capture.pattern, line_number)
-                        .merge_source(&parser_source_capture.input, line_number);
-                }
-                dbg!("SOURCE CAPTURE", &source_capture_state);
-                Ok(source_capture_state.clone())
-            })
-            .next()
-            .ok_or_else(|| "No parser contexts available".to_string())?
+        self.context.combine_parser_sources()
+    }
+
+    #[cfg(debug_assertions)]
+    pub fn combine_all_parser_sources(
+        &self,
+    ) -> Result<ParserStatementsCapture, Box<dyn std::error::Error>> {
+        self.context.combine_all_parser_sources()
+    }
+
+    /// The frames of the annotated functions the error propagated through, innermost first.
+    #[must_use]
+    #[cfg(debug_assertions)]
+    pub fn chain(&self) -> &Vec<ContextFrame<'a>> {
+        &self.chain
+    }
+
+    #[must_use]
+    #[cfg(debug_assertions)]
+    pub fn pattern_value(&self) -> &Option<String> {
+        &self.pattern_value
+    }
+
+    #[cfg(debug_assertions)]
+    pub fn set_pattern_value(&mut self, pattern_value: &impl Debug) {
+        self.pattern_value = Some(format!("{pattern_value:?}"));
+    }
+
+    #[must_use]
+    #[cfg(debug_assertions)]
+    pub fn captured_bindings(&self) -> &Vec<CapturedBinding<'a>> {
+        &self.captured_bindings
+    }
+
+    /// Records what the successful statement at `statement_index` consumed from its input and the
+    /// `Debug` rendering of the output it bound, e.g. `x` in `let (i, x) = p(i)?;`.
+    #[cfg(debug_assertions)]
+    pub fn push_captured_binding(
+        &mut self,
+        statement_index: usize,
+        consumed: &'a str,
+        captured: &impl Debug,
+    ) {
+        self.captured_bindings.push(CapturedBinding {
+            statement_index,
+            consumed,
+            captured: format!("{captured:?}"),
+        });
+    }
+
+    #[must_use]
+    #[cfg(debug_assertions)]
+    pub fn parser_name(&self) -> &Option<&'a str> {
+        &self.parser_name
+    }
+
+    #[must_use]
+    #[cfg(debug_assertions)]
+    pub fn expectation(&self) -> &Option<&'a str> {
+        &self.expectation
+    }
+
+    #[must_use]
+    #[cfg(debug_assertions)]
+    pub fn label(&self) -> &Option<&'a str> {
+        &self.label
+    }
+
+    #[must_use]
+    #[cfg(debug_assertions)]
+    pub fn help(&self) -> &Option<&'a str> {
+        &self.help
+    }
+
+    #[cfg(debug_assertions)]
+    pub fn set_parser_name(&mut self, parser_name: &'a str) {
+        self.parser_name = Some(parser_name);
+    }
+
+    #[cfg(debug_assertions)]
+    pub fn set_expectation(&mut self, expectation: &'a str) {
+        self.expectation = Some(expectation);
+    }
+
+    #[cfg(debug_assertions)]
+    pub fn set_label(&mut self, label: &'a str) {
+        self.label = Some(label);
+    }
+
+    #[cfg(debug_assertions)]
+    pub fn set_help(&mut self, help: &'a str) {
+        self.help = Some(help);
+    }
+
+    /// Records an annotated function the error propagated through. Frames are pushed as the error
+    /// travels outwards, so the last frame pushed belongs to the outermost function.
+    #[cfg(debug_assertions)]
+    pub fn push_frame(&mut self, frame: ContextFrame<'a>) {
+        self.chain.push(frame);
     }
 }
 
@@ -116,8 +267,10 @@ impl<'a> Display for ContextError<'a> {
     }
 }
 impl<'a> nom::error::ParseError<&'a str> for ContextError<'a> {
-    fn from_error_kind(input: &'a str, kind: nom::error::ErrorKind) -> Self {
-        ContextError::new()
+    fn from_error_kind(input: &'a str, _kind: nom::error::ErrorKind) -> Self {
+        let mut error = ContextError::new();
+        error.set_input(input);
+        error
     }
 
     fn append(_input: &'a str, _kind: nom::error::ErrorKind, other: Self) -> Self {
@@ -125,7 +278,9 @@ impl<'a> nom::error::ParseError<&'a str> for ContextError<'a> {
     }
 
     fn from_char(input: &'a str, _c: char) -> Self {
-        ContextError::new()
+        let mut error = ContextError::new();
+        error.set_input(input);
+        error
     }
 }
 
@@ -133,9 +288,20 @@ impl<'a> nom::error::ParseError<&'a str> for ContextError<'a> {
 #[derive(Clone, Debug, Default)]
 pub struct FunctionContext {
     pub signature: SourceCapture,
+    /// The header of the `impl` or `trait` block a method is defined in, e.g. `impl MyGrammar {`.
+    pub enclosing_item: Option<SourceCapture>,
+    /// The type the enclosing `impl` block is for, or the trait's name for trait default methods.
+    pub self_type: Option<String>,
+    /// The header of the closure the parser statements live in, e.g. `move |input| {`, for parser
+    /// factories returning `impl FnMut` and closures passed to combinators.
+    pub closure: Option<SourceCapture>,
+    /// Where a parser factory was called to create the failing parser, recorded at runtime through
+    /// `#[track_caller]`.
+    pub instantiated_at: Option<&'static Location<'static>>,
     pub parser_contexts: Option<Vec<ParserSourceCapture>>,
     pub parser_context_failure_index: Option<usize>,
     pub nested_parser_contexts: Option<Vec<ParserSourceCapture>>,
+    pub nested_parser_failure_index: Option<usize>,
     pub closing_tokens: Option<SourceCapture>,
 }
 
@@ -161,6 +327,158 @@ impl FunctionContext {
         &self.nested_parser_contexts
     }
 
+    #[must_use]
+    pub fn nested_parser_failure_index(&self) -> &Option<usize> {
+        &self.nested_parser_failure_index
+    }
+
+    /// The nested parser that failed inside the failing statement's combinator, e.g. `tag(")")` in
+    /// `delimited(tag("("), inner, tag(")"))`. It is looked up in the failing statement's
+    /// `nested_parsers`, falling back to `nested_parser_contexts` when those weren't captured.
+    #[must_use]
+    pub fn failing_nested_parser(&self) -> Option<SourceCapture> {
+        let index = self.nested_parser_failure_index?;
+        let statement = self.parser_contexts.as_ref()?.get(self.failure_index())?;
+        match statement
+            .nested_parsers
+            .as_ref()
+            .and_then(|nested_parsers| nested_parsers.get(index))
+        {
+            Some(nested_parser) => Some(nested_parser.clone()),
+            None => self
+                .nested_parser_contexts
+                .as_ref()?
+                .get(index)?
+                .merged_source()
+                .ok(),
+        }
+    }
+
+    /// The full capture of the failing nested parser, found in `nested_parser_contexts` by the position
+    /// of its ident.
+    #[must_use]
+    pub fn failing_nested_parser_context(&self) -> Option<&ParserSourceCapture> {
+        let nested_parser = self.failing_nested_parser()?;
+        self.nested_parser_contexts
+            .as_ref()?
+            .iter()
+            .find(|nested_context| {
+                nested_context.ident.line_number == nested_parser.line_number
+                    && nested_context.ident.start_column == nested_parser.start_column
+            })
+    }
+
+    /// The index of the parser statement that failed, falling back to the first statement when the
+    /// failure index wasn't recorded and clamping it to the statements that were captured.
+    #[must_use]
+    pub fn failure_index(&self) -> usize {
+        let statement_count = self.parser_contexts.as_ref().map_or(0, Vec::len);
+        self.parser_context_failure_index
+            .unwrap_or_default()
+            .min(statement_count.saturating_sub(1))
+    }
+
+    /// The index of the parser statement that failed when it is known, i.e. when it was recorded or
+    /// the function only has a single statement.
+    #[must_use]
+    pub fn recorded_failure_index(&self) -> Option<usize> {
+        let statement_count = self.parser_contexts.as_ref().map_or(0, Vec::len);
+        self.parser_context_failure_index
+            .or((statement_count == 1).then_some(0))
+            .map(|_| self.failure_index())
+    }
+
+    pub fn combine_parser_sources(&self) -> Result<SourceCapture, Box<dyn std::error::Error>> {
+        self.parser_contexts
+            .as_ref()
+            .ok_or_else(|| "No parser contexts available".to_string())?
+            .get(self.failure_index())
+            .ok_or_else(|| "No parser contexts available".to_string())?
+            .merged_source()
+    }
+
+    /// Lays every parser statement of the function out into one `SourceCapture`, recording the span of
+    /// each statement within it so the failing one and the successful ones before it can be annotated.
+    pub fn combine_all_parser_sources(
+        &self,
+    ) -> Result<ParserStatementsCapture, Box<dyn std::error::Error>> {
+        let parser_contexts = self
+            .parser_contexts
+            .as_ref()
+            .filter(|parser_contexts| !parser_contexts.is_empty())
+            .ok_or_else(|| "No parser contexts available".to_string())?;
+
+        let mut statements = ParserStatementsCapture {
+            failure_index: self.failure_index(),
+            ..ParserStatementsCapture::default()
+        };
+        for parser_source_capture in parser_contexts {
+            let (mut statement, spans) = parser_source_capture.merged_source_spans()?;
+            // `merged_source` pads the text out to its start column, which `merge_source` does again
+            let start_column = statement.start_column.unwrap_or_default();
+            statement
+                .source_text
+                .drain(..start_column.min(statement.source_text.len()));
+            statements.source.merge_source(&statement);
+            let statement_start = statements.source.source_text.len() - statement.source_text.len();
+            let shift = |span: Range<usize>| {
+                span.start - start_column + statement_start
+                    ..span.end - start_column + statement_start
+            };
+            statements.statement_spans.push(StatementSpans {
+                statement: shift(spans.statement),
+                ident: spans.ident.map(shift),
+                pattern: spans.pattern.map(shift),
+                input: spans.input.map(shift),
+            });
+        }
+        Ok(statements)
+    }
+
+    #[must_use]
+    pub fn enclosing_item(&self) -> &Option<SourceCapture> {
+        &self.enclosing_item
+    }
+
+    #[must_use]
+    pub fn self_type(&self) -> &Option<String> {
+        &self.self_type
+    }
+
+    #[must_use]
+    pub fn closure(&self) -> &Option<SourceCapture> {
+        &self.closure
+    }
+
+    #[must_use]
+    pub fn instantiated_at(&self) -> &Option<&'static Location<'static>> {
+        &self.instantiated_at
+    }
+
+    /// The name of the function, prefixed with its self type for methods, e.g. `MyGrammar::header`.
+    #[must_use]
+    pub fn qualified_name(&self) -> Option<String> {
+        let function_name = self.function_name()?;
+        Some(match &self.self_type {
+            Some(self_type) => format!("{self_type}::{function_name}"),
+            None => function_name.to_string(),
+        })
+    }
+
+    /// The name of the function, read from its captured signature.
+    #[must_use]
+    pub fn function_name(&self) -> Option<&str> {
+        let signature_text = self.signature.source_text.as_str();
+        let after_fn = signature_text
+            .split_whitespace()
+            .skip_while(|token| *token != "fn")
+            .nth(1)?;
+        let end = after_fn
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(after_fn.len());
+        Some(&after_fn[..end]).filter(|name| !name.is_empty())
+    }
+
     #[must_use]
     pub fn closing_tokens(&self) -> &Option<SourceCapture> {
         &self.closing_tokens
@@ -182,15 +500,89 @@ impl FunctionContext {
         item_fn: &ItemFn,
         signature_source_capture: &mut SourceCapture,
     ) -> Self {
-        let line_number = item_fn.sig.ident.span().start().line;
-        let sig_text = item_fn.format();
+        let (line_number, sig_text) = item_fn.format();
+        let _ = signature_source_capture
+            .set_source_text(sig_text)
+            .set_line_number(line_number)
+            .set_span(item_fn.sig.fn_token.span);
+        self.signature = std::mem::take(signature_source_capture);
+        self
+    }
+
+    /// Like [`FunctionContext::set_signature`] for a method defined in `item_impl`, which is recorded as
+    /// the enclosing item so the rendered header reads `impl MyGrammar {` followed by the method.
+    #[must_use]
+    pub fn set_impl_fn_signature(
+        mut self,
+        item_impl: &ItemImpl,
+        impl_item_fn: &ImplItemFn,
+        signature_source_capture: &mut SourceCapture,
+    ) -> Self {
+        let (line_number, header_text) = item_impl.format();
+        let mut enclosing_item = SourceCapture::default();
+        let _ = enclosing_item
+            .set_source_text(header_text)
+            .set_line_number(line_number)
+            .set_span(item_impl.impl_token.span);
+        self.enclosing_item = Some(enclosing_item);
+        self.self_type = Some(format_tokens(&item_impl.self_ty));
+        let (line_number, sig_text) = impl_item_fn.format();
+        let _ = signature_source_capture
+            .set_source_text(sig_text)
+            .set_line_number(line_number)
+            .set_span(impl_item_fn.sig.fn_token.span);
+        self.signature = std::mem::take(signature_source_capture);
+        self
+    }
+
+    /// Like [`FunctionContext::set_signature`] for a default method of `item_trait`.
+    #[must_use]
+    pub fn set_trait_fn_signature(
+        mut self,
+        item_trait: &ItemTrait,
+        trait_item_fn: &TraitItemFn,
+        signature_source_capture: &mut SourceCapture,
+    ) -> Self {
+        let (line_number, header_text) = item_trait.format();
+        let mut enclosing_item = SourceCapture::default();
+        let _ = enclosing_item
+            .set_source_text(header_text)
+            .set_line_number(line_number)
+            .set_span(item_trait.trait_token.span);
+        self.enclosing_item = Some(enclosing_item);
+        self.self_type = Some(item_trait.ident.to_string());
+        let (line_number, sig_text) = trait_item_fn.format();
         let _ = signature_source_capture
             .set_source_text(sig_text)
-            .set_line_number(line_number);
+            .set_line_number(line_number)
+            .set_span(trait_item_fn.sig.fn_token.span);
         self.signature = std::mem::take(signature_source_capture);
         self
     }
 
+    /// Records the closure the parser statements are captured from, such as the one returned by a
+    /// parser factory like `fn kv<'a>(sep: char) -> impl FnMut(&'a str) -> IResult<..>`.
+    #[must_use]
+    pub fn set_closure(
+        mut self,
+        expr_closure: &ExprClosure,
+        closure_source_capture: &mut SourceCapture,
+    ) -> Self {
+        let (line_number, closure_text) = expr_closure.format();
+        let _ = closure_source_capture
+            .set_source_text(closure_text)
+            .set_line_number(line_number)
+            .set_span(expr_closure.or1_token.span);
+        self.closure = Some(std::mem::take(closure_source_capture));
+        self
+    }
+
+    /// Records the call site of the parser factory that created the failing parser, usually
+    /// `Location::caller()` taken in a `#[track_caller]` factory.
+    pub fn set_instantiated_at(&mut self, location: &'static Location<'static>) {
+        self.instantiated_at = Some(location);
+    }
+
     pub fn set_parser_context(&mut self, parser_context_source_capture: ParserSourceCapture) {
         self.parser_contexts
             .get_or_insert_with(Vec::new)
@@ -209,6 +601,12 @@ impl FunctionContext {
             .push(nested_context);
     }
 
+    #[must_use]
+    pub fn set_nested_parser_failure_index(mut self, index: usize) -> Self {
+        self.nested_parser_failure_index = Some(index);
+        self
+    }
+
     #[must_use]
     pub fn set_closing_tokens(mut self, closing_tokens_source_capture: SourceCapture) -> Self {
         self.closing_tokens = Some(closing_tokens_source_capture);
@@ -216,6 +614,64 @@ impl FunctionContext {
     }
 }
 
+/// The input consumed and the output bound by a parser statement that succeeded before the failure.
+#[cfg(debug_assertions)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CapturedBinding<'a> {
+    pub statement_index: usize,
+    pub consumed: &'a str,
+    pub captured: String,
+}
+
+/// An annotated function an error propagated through on its way out of the parser-chain, along with
+/// the file it lives in and the input it was called with.
+#[cfg(debug_assertions)]
+#[derive(Clone, Debug, Default)]
+pub struct ContextFrame<'a> {
+    pub context: FunctionContext,
+    pub file: Option<&'static str>,
+    pub input: Option<&'a str>,
+}
+
+#[cfg(debug_assertions)]
+impl<'a> ContextFrame<'a> {
+    #[must_use]
+    pub fn new(context: FunctionContext) -> Self {
+        Self {
+            context,
+            file: None,
+            input: None,
+        }
+    }
+
+    #[must_use]
+    pub fn context(&self) -> &FunctionContext {
+        &self.context
+    }
+
+    #[must_use]
+    pub fn file(&self) -> &Option<&'static str> {
+        &self.file
+    }
+
+    #[must_use]
+    pub fn input(&self) -> &Option<&'a str> {
+        &self.input
+    }
+
+    #[must_use]
+    pub fn set_file(mut self, file: &'static str) -> Self {
+        self.file = Some(file);
+        self
+    }
+
+    #[must_use]
+    pub fn set_input(mut self, input: &'a str) -> Self {
+        self.input = Some(input);
+        self
+    }
+}
+
 #[cfg(debug_assertions)]
 impl AsRef<FunctionContext> for FunctionContext {
     fn as_ref(&self) -> &FunctionContext {
@@ -224,13 +680,31 @@ impl AsRef<FunctionContext> for FunctionContext {
 }
 
 #[cfg(debug_assertions)]
-#[derive(Clone, Debug, Default, PartialEq)]
+#[derive(Clone, Debug, Default)]
 pub struct SourceCapture {
     pub source_text: String,
     pub line_number: usize,
+    pub end_line_number: Option<usize>,
     pub start_column: Option<usize>,
     pub end_column: Option<usize>,
     pub span_length: Option<usize>,
+    /// The span the capture was built from while the annotating macro expands, as registered within
+    /// [`to_tokens::with_span_registry`]. It lets the generated code fall back to `line!()` and
+    /// `column!()` when span locations are unavailable and is always `None` at runtime.
+    pub span_index: Option<usize>,
+}
+
+// The span index only refers to the expansion the capture was built in, so it isn't compared
+#[cfg(debug_assertions)]
+impl PartialEq for SourceCapture {
+    fn eq(&self, other: &Self) -> bool {
+        self.source_text == other.source_text
+            && self.line_number == other.line_number
+            && self.end_line_number == other.end_line_number
+            && self.start_column == other.start_column
+            && self.end_column == other.end_column
+            && self.span_length == other.span_length
+    }
 }
 
 #[cfg(debug_assertions)]
@@ -272,6 +746,12 @@ impl SourceCapture {
         self
     }
 
+    #[must_use]
+    pub fn set_end_line_number(&mut self, end_line_number: usize) -> &mut Self {
+        self.end_line_number = Some(end_line_number);
+        self
+    }
+
     #[must_use]
     pub fn set_start_column(&mut self, start_column: usize) -> &mut Self {
         self.start_column = Some(start_column);
@@ -290,11 +770,33 @@ impl SourceCapture {
         self
     }
 
-    #[must_use]
+    /// Records the span the capture starts at so its location can be recovered by the generated code,
+    /// see [`crate::to_tokens::LocationMode`].
+    pub fn set_span(&mut self, span: Span) -> &mut Self {
+        self.span_index = to_tokens::register_span(span);
+        self
+    }
+
     pub fn get_span_length(&self) -> Result<usize, Box<dyn std::error::Error>> {
-        //TODO: rethink this. There may be some issues when the span is on a different line causing issues with the span_length calulation which assumes the parser is all on a single line
-        Ok(self.end_column.expect("end_column not found")
-            - self.start_column.expect("start_column not found"))
+        if self.is_multiline() {
+            return Ok(self.source_text.len());
+        }
+        let end_column = self.end_column.ok_or("end_column not found")?;
+        let start_column = self.start_column.ok_or("start_column not found")?;
+        Ok(end_column - start_column)
+    }
+
+    /// The last line the captured source occupies; the same as `line_number` for single line captures.
+    #[must_use]
+    pub fn get_end_line_number(&self) -> usize {
+        self.end_line_number
+            .unwrap_or(self.line_number)
+            .max(self.line_number)
+    }
+
+    #[must_use]
+    pub fn is_multiline(&self) -> bool {
+        self.get_end_line_number() > self.line_number
     }
 
     #[must_use]
@@ -302,47 +804,257 @@ impl SourceCapture {
         if self.source_text.is_empty() {
             self.set_source_text(source_text)
                 .set_line_number(span.start().line)
+                .set_end_line_number(span.end().line)
                 .set_start_column(span.start().column)
-                .set_end_column(span.end().column);
+                .set_end_column(span.end().column)
+                .set_span(span);
         }
         self
     }
 
-    fn merge_source(
-        &mut self,
-        source_capture_input: &SourceCapture,
-        line_number: usize,
-    ) -> &mut Self {
-        if source_capture_input.line_number == line_number {
-            if self.start_column.is_none() {
-                self.line_number = line_number;
-                if let Some(span_length) = self.span_length {
-                    self.span_length =
-                        Some(span_length + source_capture_input.start_column.unwrap());
-                } else {
-                    self.span_length = Some(source_capture_input.start_column.unwrap());
-                }
-                self.source_text.push_str(
-                    " ".repeat(source_capture_input.start_column.unwrap())
-                        .as_str(),
-                );
-                self.source_text.push_str(&source_capture_input.source_text);
-                self.start_column = source_capture_input.start_column;
-            } else {
-                self.source_text.push_str(&source_capture_input.source_text);
+    /// Appends `source_capture_input` to this capture, laying it out by its line and column so that
+    /// parser expressions wrapped across several lines (e.g. by rustfmt) keep their shape.
+    ///
+    /// Pieces on the line we are currently on are concatenated directly, while a piece that starts on a
+    /// later line begins a new line indented to its start column. Afterwards `span_length` holds the end of
+    /// the merged text so that `start_column..span_length` covers everything that was merged.
+    fn merge_source(&mut self, source_capture_input: &SourceCapture) -> &mut Self {
+        let Some(start_column) = source_capture_input.start_column else {
+            return self;
+        };
+        if self.start_column.is_none() {
+            self.line_number = source_capture_input.line_number;
+            self.start_column = Some(start_column);
+            self.source_text = " ".repeat(start_column);
+        } else {
+            let current_line = self.get_end_line_number();
+            if source_capture_input.line_number > current_line {
+                self.source_text
+                    .push_str(&"\n".repeat(source_capture_input.line_number - current_line));
+                self.source_text.push_str(&" ".repeat(start_column));
+            }
+        }
+        self.source_text.push_str(&source_capture_input.source_text);
+        self.end_line_number = Some(
+            source_capture_input
+                .get_end_line_number()
+                .max(self.get_end_line_number()),
+        );
+        self.end_column = source_capture_input.end_column;
+        self.span_length = Some(self.source_text.len());
+        self
+    }
+}
+
+/// Every parser statement of a function laid out as one source snippet, see
+/// [`FunctionContext::combine_all_parser_sources`].
+#[cfg(debug_assertions)]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ParserStatementsCapture {
+    pub source: SourceCapture,
+    pub statement_spans: Vec<StatementSpans>,
+    pub failure_index: usize,
+}
+
+#[cfg(debug_assertions)]
+impl ParserStatementsCapture {
+    /// Locates `source_capture` inside the statement at `statement_index` by its line and column,
+    /// falling back to searching the statement's text when the pieces weren't laid out by column.
+    #[must_use]
+    pub fn span_of(
+        &self,
+        source_capture: &SourceCapture,
+        statement_index: usize,
+    ) -> Option<Range<usize>> {
+        let statement = &self.statement_spans.get(statement_index)?.statement;
+        let text = self.source.source_text.as_str();
+        let needle = source_capture.source_text.trim();
+        if needle.is_empty() {
+            return None;
+        }
+        let by_position = source_capture
+            .line_number
+            .checked_sub(self.source.line_number)
+            .and_then(|line_index| {
+                let line_start = std::iter::once(0)
+                    .chain(text.match_indices('\n').map(|(index, _)| index + 1))
+                    .nth(line_index)?;
+                let start = line_start + source_capture.start_column?;
+                Some(start..start + needle.len())
+            })
+            .filter(|span| {
+                span.start >= statement.start
+                    && span.end <= statement.end
+                    && text.get(span.clone()) == Some(needle)
+            });
+        by_position.or_else(|| {
+            let start = statement.start + text.get(statement.clone())?.find(needle)?;
+            Some(start..start + needle.len())
+        })
+    }
+
+    /// Narrows the captured source to `context_lines` lines above and below the failing statement,
+    /// dropping the statements that no longer fit entirely within it.
+    #[must_use]
+    pub fn window(&self, context_lines: usize) -> Self {
+        let text = self.source.source_text.as_str();
+        let Some(failing) = self.statement_spans.get(self.failure_index) else {
+            return self.clone();
+        };
+        let line_of = |offset: usize| text[..offset.min(text.len())].matches('\n').count();
+        let line_count = text.matches('\n').count() + 1;
+        let first_line = line_of(failing.statement.start).saturating_sub(context_lines);
+        let last_line = (line_of(failing.statement.end) + context_lines).min(line_count - 1);
+
+        let line_starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(index, _)| index + 1))
+            .collect::<Vec<_>>();
+        let window_start = line_starts[first_line];
+        let window_end = line_starts
+            .get(last_line + 1)
+            .map_or(text.len(), |next_line_start| next_line_start - 1);
+
+        let mut window = Self {
+            source: self.source.clone(),
+            statement_spans: Vec::new(),
+            failure_index: 0,
+        };
+        window.source.source_text = text[window_start..window_end].to_string();
+        window.source.line_number += first_line;
+        window.source.end_line_number = Some(self.source.line_number + last_line);
+        window.source.span_length = Some(window.source.source_text.len());
+        for (index, spans) in self.statement_spans.iter().enumerate() {
+            if index == self.failure_index {
+                window.failure_index = window.statement_spans.len();
             }
-            if let Some(span_length) = self.span_length {
-                self.span_length = Some(
-                    span_length
-                        + source_capture_input
-                            .get_span_length()
-                            .expect("Could not calculate source_capture's span_length"),
+            if spans.statement.start >= window_start && spans.statement.end <= window_end {
+                window.statement_spans.push(
+                    spans.map_spans(|span| span.start - window_start..span.end - window_start),
                 );
             }
-            self.end_column = source_capture_input.end_column;
         }
+        window
+    }
 
-        self
+    /// Replaces the captured source with the lines of `file_source` it was captured from, extended by
+    /// `surrounding_lines` lines above and below but kept within the 1-based line numbers `bounds`.
+    ///
+    /// Fails when the capture has no location to look the file up by, or when the file no longer
+    /// contains the captured statements at their recorded locations, e.g. because it was edited since
+    /// compilation.
+    pub fn with_file_source(
+        &self,
+        file_source: &str,
+        surrounding_lines: usize,
+        bounds: RangeInclusive<usize>,
+    ) -> Result<Self, FileSourceMismatch> {
+        let text = self.source.source_text.as_str();
+        let file_lines = file_source.lines().collect::<Vec<_>>();
+        let captured_first_line = self.source.line_number;
+        let captured_last_line = captured_first_line + text.matches('\n').count();
+        if captured_first_line == 0 {
+            return Err(FileSourceMismatch::NoLocation);
+        }
+        if captured_last_line > file_lines.len() {
+            return Err(FileSourceMismatch::Changed);
+        }
+        // Line numbers are 1-based, so a bound of 0 is as good as none
+        let first_line = captured_first_line
+            .saturating_sub(surrounding_lines)
+            .max(*bounds.start())
+            .max(1)
+            .min(captured_first_line);
+        let last_line = (captured_last_line + surrounding_lines)
+            .min(*bounds.end())
+            .min(file_lines.len())
+            .max(captured_last_line);
+        let excerpt = file_lines[first_line - 1..last_line].join("\n");
+
+        // Captured pieces are laid out at their columns, so an offset into the captured text maps to
+        // the same line and character column in the file
+        let excerpt_line_starts = std::iter::once(0)
+            .chain(excerpt.match_indices('\n').map(|(index, _)| index + 1))
+            .collect::<Vec<_>>();
+        let map_offset = |offset: usize| {
+            let line_start = text[..offset].rfind('\n').map_or(0, |index| index + 1);
+            let line_index =
+                captured_first_line - first_line + text[..offset].matches('\n').count();
+            let column = text[line_start..offset].chars().count();
+            let excerpt_line_start = excerpt_line_starts[line_index];
+            let excerpt_line = excerpt[excerpt_line_start..]
+                .split('\n')
+                .next()
+                .unwrap_or_default();
+            excerpt_line_start
+                + excerpt_line
+                    .char_indices()
+                    .nth(column)
+                    .map_or(excerpt_line.len(), |(index, _)| index)
+        };
+        let map_span = |span: Range<usize>| map_offset(span.start)..map_offset(span.end);
+
+        let without_whitespace = |text: &str| {
+            text.chars()
+                .filter(|character| !character.is_whitespace())
+                .collect::<String>()
+        };
+        let mut statement_spans = Vec::new();
+        for spans in &self.statement_spans {
+            let mapped = spans.map_spans(map_span);
+            let captured = text.get(spans.statement.clone());
+            let in_file = excerpt.get(mapped.statement.clone());
+            if captured.zip(in_file).is_none_or(|(captured, in_file)| {
+                without_whitespace(captured) != without_whitespace(in_file)
+            }) {
+                return Err(FileSourceMismatch::Changed);
+            }
+            statement_spans.push(mapped);
+        }
+
+        let mut source = self.source.clone();
+        source.source_text = excerpt;
+        source.line_number = first_line;
+        source.end_line_number = Some(last_line);
+        source.start_column = Some(0);
+        source.span_length = Some(source.source_text.len());
+        Ok(Self {
+            source,
+            statement_spans,
+            failure_index: self.failure_index,
+        })
+    }
+}
+
+/// Why [`ParserStatementsCapture::with_file_source`] couldn't show the source file.
+#[cfg(debug_assertions)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileSourceMismatch {
+    /// The capture has no line numbers, e.g. because span locations weren't available.
+    NoLocation,
+    /// The file no longer contains the captured statements at their recorded locations.
+    Changed,
+}
+
+/// Where a parser statement and its parts are located within a merged source text.
+#[cfg(debug_assertions)]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StatementSpans {
+    pub statement: Range<usize>,
+    pub ident: Option<Range<usize>>,
+    pub pattern: Option<Range<usize>>,
+    pub input: Option<Range<usize>>,
+}
+
+#[cfg(debug_assertions)]
+impl StatementSpans {
+    #[must_use]
+    pub fn map_spans(&self, f: impl Fn(Range<usize>) -> Range<usize>) -> Self {
+        Self {
+            statement: f(self.statement.clone()),
+            ident: self.ident.clone().map(&f),
+            pattern: self.pattern.clone().map(&f),
+            input: self.input.clone().map(&f),
+        }
     }
 }
 
@@ -354,6 +1066,10 @@ pub struct ParserSourceCapture {
     pub pattern: SourceCapture,
     pub nested_parsers: Option<Vec<SourceCapture>>,
     pub input: SourceCapture,
+    /// The parser expression as written when the parser is wrapped in `annotate` or `Annotated`,
+    /// e.g. `annotate(tag("world"))(input)`. It is shown in place of the ident, pattern and input,
+    /// which only cover the wrapped parser and its input.
+    pub expression: Option<SourceCapture>,
 }
 
 #[cfg(debug_assertions)]
@@ -395,6 +1111,223 @@ impl ParserSourceCapture {
     pub fn set_input(&mut self, input_source_capture: &SourceCapture) {
         self.input = input_source_capture.clone();
     }
+    /// Lays the binding, ident, pattern and input captures out into a single `SourceCapture` spanning
+    /// every line the parser expression occupies.
+    pub fn merged_source(&self) -> Result<SourceCapture, Box<dyn std::error::Error>> {
+        self.merged_source_spans()
+            .map(|(source_capture, _)| source_capture)
+    }
+
+    /// Like [`ParserSourceCapture::merged_source`], additionally returning where the ident, pattern and
+    /// input ended up within the merged text.
+    pub fn merged_source_spans(
+        &self,
+    ) -> Result<(SourceCapture, StatementSpans), Box<dyn std::error::Error>> {
+        fn merge_piece(
+            source_capture_state: &mut SourceCapture,
+            piece: &SourceCapture,
+        ) -> Option<Range<usize>> {
+            piece.start_column?;
+            source_capture_state.merge_source(piece);
+            let end = source_capture_state.source_text.len();
+            Some(end - piece.source_text.len()..end)
+        }
+
+        let mut source_capture_state = SourceCapture::default();
+        if let Some(binding_pattern) = &self.binding_pattern {
+            source_capture_state.merge_source(binding_pattern);
+        }
+        let (ident, pattern, input) = match &self.expression {
+            Some(expression) => {
+                merge_piece(&mut source_capture_state, expression);
+                // The expression keeps its layout, so the pieces are found by their line and column
+                let text = source_capture_state.source_text.as_str();
+                let first_line = source_capture_state.line_number;
+                (
+                    span_at(text, first_line, &self.ident),
+                    span_at(text, first_line, &self.pattern),
+                    span_at(text, first_line, &self.input),
+                )
+            }
+            None => (
+                merge_piece(&mut source_capture_state, &self.ident),
+                merge_piece(&mut source_capture_state, &self.pattern),
+                merge_piece(&mut source_capture_state, &self.input),
+            ),
+        };
+        let Some(start_column) = source_capture_state.start_column else {
+            return Err("Parser source capture has no column information".into());
+        };
+        let spans = StatementSpans {
+            statement: start_column..source_capture_state.source_text.len(),
+            ident,
+            pattern,
+            input,
+        };
+        Ok((source_capture_state, spans))
+    }
+
+    /// The name the input argument was given at the call site, e.g. `input_name` for `tag("world")(input_name)`.
+    #[must_use]
+    pub fn input_name(&self) -> Option<&str> {
+        Some(strip_call_parens(&self.input.source_text)).filter(|name| !name.is_empty())
+    }
+
+    /// The source text of the pattern the parser was constructed with, e.g. `"world"` for `tag("world")`.
+    #[must_use]
+    pub fn pattern_text(&self) -> Option<&str> {
+        // Parsers invoked through `Parser::parse` carry the method in their pattern, see `from_expr`
+        let pattern = self.pattern.source_text.trim();
+        let pattern = pattern.strip_suffix(".parse").unwrap_or(pattern);
+        let pattern = strip_call_parens(pattern);
+        // rustfmt ends arguments it wraps onto their own lines with a comma
+        let pattern = pattern.strip_suffix(',').map_or(pattern, str::trim_end);
+        Some(pattern).filter(|pattern| !pattern.is_empty())
+    }
+
+    /// Builds the capture of a parser invocation such as `tag("world")(input)`, `alpha1(input)?`,
+    /// `tag("a").and(tag("b")).parse(input)` or `self.header(input)`, returning `None` for expressions
+    /// that aren't one, including constructors like `Ok((input, value))` or `String::from(text)`.
+    ///
+    /// The ident is the path of the parser function, the pattern everything between it and the input
+    /// argument (including `.parse` for method chains) and the nested parsers are the arguments of the
+    /// parser function that are parsers themselves, e.g. `inner` in `delimited(open, inner, close)`.
+    /// Parsers wrapped in `annotate` or `Annotated` are captured through the wrapper, which is kept in
+    /// the `expression` as written.
+    #[must_use]
+    pub fn from_expr(expr: &Expr) -> Option<Self> {
+        match expr {
+            Expr::Try(expr_try) => Self::from_expr(&expr_try.expr),
+            Expr::Paren(expr_paren) => Self::from_expr(&expr_paren.expr),
+            Expr::Group(expr_group) => Self::from_expr(&expr_group.expr),
+            Expr::Call(expr_call) => {
+                let annotated = annotated_parser(&expr_call.func);
+                let parser = strip_parens(annotated);
+                if parser_ident(parser).is_some_and(is_constructor) {
+                    return None;
+                }
+                let mut capture = Self::from_parser(parser)?;
+                capture.input = capture_parenthesized(&expr_call.paren_token, &expr_call.args)?;
+                if !std::ptr::eq(annotated, expr_call.func.as_ref()) {
+                    capture.expression = capture_tokens(expr_call.to_token_stream());
+                }
+                Some(capture)
+            }
+            Expr::MethodCall(expr_method_call) if expr_method_call.method == "parse" => {
+                let annotated = annotated_parser(&expr_method_call.receiver);
+                let receiver = strip_parens(annotated);
+                let mut tokens = receiver.to_token_stream();
+                expr_method_call.dot_token.to_tokens(&mut tokens);
+                expr_method_call.method.to_tokens(&mut tokens);
+                expr_method_call.turbofish.to_tokens(&mut tokens);
+                let mut capture = Self::from_parser_tokens(receiver, tokens)?;
+                capture.input =
+                    capture_parenthesized(&expr_method_call.paren_token, &expr_method_call.args)?;
+                if !std::ptr::eq(annotated, expr_method_call.receiver.as_ref()) {
+                    capture.expression = capture_tokens(expr_method_call.to_token_stream());
+                }
+                Some(capture)
+            }
+            // Parsers implemented as methods, e.g. `self.header(input)`
+            Expr::MethodCall(expr_method_call)
+                if !expr_method_call.args.is_empty()
+                    && matches!(
+                        expr_method_call.receiver.as_ref(),
+                        Expr::Path(expr_path) if expr_path.path.is_ident("self")
+                    ) =>
+            {
+                let mut tokens = expr_method_call.receiver.to_token_stream();
+                expr_method_call.dot_token.to_tokens(&mut tokens);
+                expr_method_call.method.to_tokens(&mut tokens);
+                expr_method_call.turbofish.to_tokens(&mut tokens);
+                let mut capture = Self {
+                    ident: capture_tokens(tokens)?,
+                    ..Self::default()
+                };
+                capture.input =
+                    capture_parenthesized(&expr_method_call.paren_token, &expr_method_call.args)?;
+                Some(capture)
+            }
+            _ => None,
+        }
+    }
+
+    /// Like [`ParserSourceCapture::from_expr`] for a `let` statement, additionally capturing the binding
+    /// pattern, e.g. `let (remaining, captured) = ` in `let (remaining, captured) = tag("world")(input)?;`.
+    #[must_use]
+    pub fn from_local(local: &Local) -> Option<Self> {
+        let init = local.init.as_ref()?;
+        let mut capture = Self::from_expr(&init.expr)?;
+        let mut tokens = local.let_token.to_token_stream();
+        local.pat.to_tokens(&mut tokens);
+        init.eq_token.to_tokens(&mut tokens);
+        let mut binding_pattern = capture_tokens(tokens)?;
+        // The pieces of a statement are concatenated when merged, so the space between the `=` and
+        // the parser is part of the binding pattern
+        binding_pattern.source_text.push(' ');
+        binding_pattern.end_column = binding_pattern.end_column.map(|end_column| end_column + 1);
+        capture.binding_pattern = Some(binding_pattern);
+        Some(capture)
+    }
+
+    /// Builds the captures of the parser arguments of the parser invoked in `expr`, such as `tag("(")`,
+    /// `inner` and `tag(")")` for `delimited(tag("("), inner, tag(")"))(input)`, for use as a
+    /// function's `nested_parser_contexts`.
+    #[must_use]
+    pub fn nested_from_expr(expr: &Expr) -> Vec<Self> {
+        let parser = match expr {
+            Expr::Try(expr_try) => return Self::nested_from_expr(&expr_try.expr),
+            Expr::Paren(expr_paren) => return Self::nested_from_expr(&expr_paren.expr),
+            Expr::Group(expr_group) => return Self::nested_from_expr(&expr_group.expr),
+            Expr::Call(expr_call) => annotated_parser(&expr_call.func),
+            Expr::MethodCall(expr_method_call) if expr_method_call.method == "parse" => {
+                annotated_parser(&expr_method_call.receiver)
+            }
+            _ => return Vec::new(),
+        };
+        parser_arguments(parser)
+            .filter_map(Self::from_parser)
+            .collect()
+    }
+
+    /// Captures the ident, pattern and nested parsers of a parser expression that hasn't been
+    /// invoked yet, e.g. `tag("world")` or `alpha1`.
+    fn from_parser(parser: &Expr) -> Option<Self> {
+        Self::from_parser_tokens(parser, parser.to_token_stream())
+    }
+
+    /// Like [`ParserSourceCapture::from_parser`] where `tokens` are the tokens of `parser` followed by
+    /// anything that should end up in the pattern, such as `.parse`.
+    fn from_parser_tokens(parser: &Expr, tokens: TokenStream) -> Option<Self> {
+        let ident = parser_ident(parser)?.to_token_stream();
+        let ident_length = ident.clone().into_iter().count();
+        let mut capture = Self {
+            ident: capture_tokens(ident.clone())?,
+            ..Self::default()
+        };
+
+        // The pattern is whatever follows the ident in the parser expression, which only starts the
+        // expression's tokens when it isn't nested in a receiver, e.g. `(tag("a")).and(tag("b"))`
+        let starts_with_ident = tokens
+            .clone()
+            .into_iter()
+            .take(ident_length)
+            .map(|token| token.to_string())
+            .eq(ident.into_iter().map(|token| token.to_string()));
+        if starts_with_ident {
+            if let Some(pattern) = capture_tokens(tokens.into_iter().skip(ident_length).collect()) {
+                capture.pattern = pattern;
+            }
+        }
+
+        for argument in parser_arguments(parser) {
+            if let Some(argument_capture) = capture_tokens(argument.to_token_stream()) {
+                capture.push_nested_parser_source(&argument_capture);
+            }
+        }
+        Some(capture)
+    }
+
     pub fn push_nested_parser_source(&mut self, parser_source_capture: &SourceCapture) {
         self.nested_parsers
             .get_or_insert_with(Vec::new)
@@ -408,125 +1341,505 @@ impl AsRef<ParserSourceCapture> for ParserSourceCapture {
         self
     }
 }
-trait RemoveWhitespace {
-    fn remove_whitespace(&mut self) -> String
-    where
-        Self: ToString,
-    {
-        self.to_string().split_whitespace().collect::<String>()
+/// The parser wrapped by the [`annotate`](crate::annotate::annotate) combinator or the
+/// [`Annotated`](crate::annotate::Annotated) builder, which are transparent to the captured source, e.g.
+/// `tag("world")` for `annotate(tag("world"))` or `Annotated::new(tag("world")).name("greeting")`.
+#[cfg(debug_assertions)]
+fn annotated_parser(parser: &Expr) -> &Expr {
+    match parser {
+        Expr::Call(expr_call) if expr_call.args.len() == 1 => {
+            let Expr::Path(expr_path) = expr_call.func.as_ref() else {
+                return parser;
+            };
+            let segments = expr_path
+                .path
+                .segments
+                .iter()
+                .map(|segment| segment.ident.to_string())
+                .collect::<Vec<_>>();
+            match segments
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .as_slice()
+            {
+                [.., "annotate"] | [.., "Annotated", "new"] => annotated_parser(&expr_call.args[0]),
+                _ => parser,
+            }
+        }
+        Expr::MethodCall(expr_method_call)
+            if matches!(
+                expr_method_call.method.to_string().as_str(),
+                "name" | "expect" | "label" | "help"
+            ) =>
+        {
+            let receiver = annotated_parser(&expr_method_call.receiver);
+            // Only builder calls on an `Annotated` parser are skipped
+            if std::ptr::eq(receiver, expr_method_call.receiver.as_ref()) {
+                parser
+            } else {
+                receiver
+            }
+        }
+        _ => parser,
     }
 }
-impl RemoveWhitespace for TokenStream {}
 
-trait FnSignatureFormat {
-    fn format(&self) -> String;
+/// `parser` without the parentheses or invisible groups around it, e.g. `tag("x")` for `(tag("x"))`.
+#[cfg(debug_assertions)]
+fn strip_parens(parser: &Expr) -> &Expr {
+    match parser {
+        Expr::Paren(expr_paren) => strip_parens(&expr_paren.expr),
+        Expr::Group(expr_group) => strip_parens(&expr_group.expr),
+        _ => parser,
+    }
 }
 
-impl FnSignatureFormat for ItemFn {
-    /*
-      Unfortunately, when we try to use the `syn::Signature` directly,
-      regardless of whether in token form using the `quote!{...}` macro or converted to a String using the `format!(...)` macro, the `syn::Signature` output field introduces a `/n` in between a second lifetime definition and its ident.
-
-      For example:
-      if the original source code's output is:
-          `-> IResult<&'a str, &'a str, ContextError<'a>>`
-      it becomes:
-          `-> IResult<&'a str, &'a/nstr, ContextError<'a>>`
+/// Whether `path` constructs a value rather than naming a parser, e.g. the `Ok` in
+/// `Ok((input, value))`, a tuple struct or `String::from`.
+#[cfg(debug_assertions)]
+fn is_constructor(path: &ExprPath) -> bool {
+    let segments = &path.path.segments;
+    segments.last().is_some_and(|segment| {
+        let ident = segment.ident.to_string();
+        ident.starts_with(char::is_uppercase)
+            || (segments.len() > 1
+                && matches!(
+                    ident.as_str(),
+                    "new" | "from" | "default" | "with_capacity" | "from_str" | "from_iter"
+                ))
+    })
+}
 
-      but when we use the `syn::Signature.output` directly with `format!(...)` it no longer appears; however, when we use `quote!{...}`, the newline is still introduced even with the manually extracted fields. Although, the newline is rendered as an actual newline in the terminal output.
+/// The path naming the parser function in a parser expression, e.g. `tag` in `tag("world")` or in
+/// `tag("a").and(tag("b"))`.
+#[cfg(debug_assertions)]
+fn parser_ident(parser: &Expr) -> Option<&ExprPath> {
+    match parser {
+        Expr::Path(expr_path) => Some(expr_path),
+        Expr::Call(expr_call) => parser_ident(&expr_call.func),
+        Expr::MethodCall(expr_method_call) => parser_ident(&expr_method_call.receiver),
+        Expr::Paren(expr_paren) => parser_ident(&expr_paren.expr),
+        Expr::Group(expr_group) => parser_ident(&expr_group.expr),
+        _ => None,
+    }
+}
 
-      Another unfortunate side effect, is that the conversion from TokenStream to String on the `syn::Signature` or its fields directly, introduces some formatting artifacts, most notably, extra whitespace in the conversion from `syn::Lifetimes` so we must explicitly handle those also.
+/// The arguments of a parser expression that are parsers themselves. Literals, references and
+/// `SCREAMING_CASE` constants are patterns rather than parsers, so they are skipped.
+#[cfg(debug_assertions)]
+fn parser_arguments(parser: &Expr) -> impl Iterator<Item = &Expr> {
+    let arguments = match parser {
+        Expr::Call(expr_call) => Some(expr_call.args.iter()),
+        _ => None,
+    };
+    arguments
+        .into_iter()
+        .flatten()
+        .filter(|argument| match argument {
+            Expr::Call(_) | Expr::MethodCall(_) | Expr::Closure(_) | Expr::Macro(_) => true,
+            Expr::Path(expr_path) => expr_path.path.segments.last().is_some_and(|segment| {
+                let ident = segment.ident.to_string();
+                ident.chars().any(char::is_lowercase)
+            }),
+            _ => false,
+        })
+}
 
-      One last unfortunate occurance is that when using `quote!{...}`, there is no way to include the opening brace for the function's code block without it being surrounded by quotes. This is because it must be rendered as a `proc_macro2::Literal`. And `quote!` doesn't support bracket escaping such as:
-          `quote!{ #some_output {{ <- not supported}`.
-      This is a very niche case so I'm sure the value of allowing that capability is not worth the implementation effort since the majority of users wouldn't benefit from it. Would have been useful though..
+/// Captures the text and location of `tokens`, laid out as they were written when span locations are
+/// available.
+#[cfg(debug_assertions)]
+fn capture_tokens(tokens: TokenStream) -> Option<SourceCapture> {
+    let span = tokens.clone().into_iter().next()?.span();
+    let start = span.start();
+    let end = tokens.clone().into_iter().last()?.span().end();
+    let source_text = match layout_tokens(tokens.clone(), end, "") {
+        Some((_, text)) => text.chars().skip(start.column).collect(),
+        None => tokens.to_string(),
+    };
+    Some(SourceCapture {
+        source_text,
+        line_number: start.line,
+        end_line_number: Some(end.line),
+        start_column: Some(start.column),
+        end_column: Some(end.column),
+        span_length: None,
+        span_index: to_tokens::register_span(span),
+    })
+}
 
-      Note: Since our errors are being rendered with annotate_snippets at the final step in the process and since the annotation of the specific line the error occurs on happens after the function signature, we only need the opening brace in the snippet created from this output which will be followed by another snippet with the actual error occurance.
+/// Where `piece` sits within `text`, a capture laid out at its columns starting at `first_line`.
+#[cfg(debug_assertions)]
+fn span_at(text: &str, first_line: usize, piece: &SourceCapture) -> Option<Range<usize>> {
+    let line_start = std::iter::once(0)
+        .chain(text.match_indices('\n').map(|(index, _)| index + 1))
+        .nth(piece.line_number.checked_sub(first_line)?)?;
+    let start = line_start
+        + text[line_start..]
+            .char_indices()
+            .nth(piece.start_column?)
+            .map(|(index, _)| index)?;
+    let span = start..start + piece.source_text.len();
+    (text.get(span.clone()) == Some(piece.source_text.as_str())).then_some(span)
+}
 
-      Something like this would have also been applicable to this case:
-          ```
-            pub struct Brace {
-                 span: Span
-            }
+/// Captures a parenthesized argument list, parentheses included, e.g. `(input)`.
+#[cfg(debug_assertions)]
+fn capture_parenthesized(
+    paren_token: &Paren,
+    arguments: &Punctuated<Expr, Comma>,
+) -> Option<SourceCapture> {
+    let mut tokens = TokenStream::new();
+    paren_token.surround(&mut tokens, |tokens| arguments.to_tokens(tokens));
+    capture_tokens(tokens)
+}
 
-            pub struct Braces {
-                  open: Brace,
-                  close: Brace,
+/// Strips the parentheses a call argument was captured with, e.g. `("world")` becomes `"world"`.
+#[cfg(debug_assertions)]
+fn strip_call_parens(text: &str) -> &str {
+    let text = text.trim();
+    text.strip_prefix('(')
+        .and_then(|text| text.strip_suffix(')'))
+        .filter(|inner| {
+            // `(a)(b)` starts and ends with parens without being wrapped in a single pair, while the
+            // parens in string and char literals such as `(")")` or `('(')` don't count
+            let mut depth = 0_usize;
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => {
+                        while let Some(c) = chars.next() {
+                            match c {
+                                '\\' => {
+                                    chars.next();
+                                }
+                                '"' => break,
+                                _ => {}
+                            }
+                        }
+                    }
+                    '\'' if chars.as_str().chars().nth(1) == Some('\'') => {
+                        chars.nth(1);
+                    }
+                    '(' => depth += 1,
+                    ')' if depth == 0 => return false,
+                    ')' => depth -= 1,
+                    _ => {}
+                }
             }
+            true
+        })
+        .map_or(text, str::trim)
+}
 
-            pub struct Block {
-                  pub braces: Braces,
-                  pub stmts: Vec<Stmt>,
-            }
+#[cfg(debug_assertions)]
+trait FnSignatureFormat {
+    /// Returns the line the signature starts on and its text, up to and including the opening brace of
+    /// the function's block.
+    fn format(&self) -> (usize, String);
+}
 
-            // Use:
-            let vis = item_fn.vis;
-            let sig = item_fn.sig;
-            let brace = item_fn.block.braces.open;
-            let fn_signature_text = quote!(#vis #sig #brace);
-          ```
-     Lamentably, without a significant rewrite to syn and proc_macro, this won't be feasable.
+#[cfg(debug_assertions)]
+impl FnSignatureFormat for ItemFn {
+    /*
+      Rendering the `syn::Signature` through its `TokenStream` loses the original layout and introduces
+      spacing artifacts (`& 'a str`, `IResult < & 'a str >`, ...) that can only be patched over with
+      string replacements, which break down as soon as where clauses, bounds, `impl Trait` arguments,
+      const generics or `dyn` types are involved.
+
+      When span locations are available, every token is instead placed back at the line and column it
+      was written at, which reproduces the user's signature exactly, bar comments. Otherwise the
+      signature is printed by `prettyplease` as a function with an empty body, which lays it out the way
+      rustfmt would, and the empty body's closing brace is dropped.
     */
+    fn format(&self) -> (usize, String) {
+        let mut tokens = self.vis.to_token_stream();
+        self.sig.to_tokens(&mut tokens);
+        let open_brace = self.block.brace_token.span.open().start();
+        layout_tokens(tokens, open_brace, "{").unwrap_or_else(|| {
+            (
+                self.sig.ident.span().start().line,
+                format_signature(&self.vis, &self.sig),
+            )
+        })
+    }
+}
 
-    fn format(&self) -> String {
-        let vis = self.vis.to_token_stream();
-        let constness = if self.sig.constness.is_some() {
-            format!("{} ", self.sig.constness.to_token_stream())
-        } else {
-            String::new()
-        };
-        let asyncness = if self.sig.asyncness.is_some() {
-            format!("{} ", self.sig.asyncness.to_token_stream())
-        } else {
-            String::new()
-        };
-        let unsafety = if self.sig.unsafety.is_some() {
-            format!("{} ", self.sig.unsafety.to_token_stream())
-        } else {
-            String::new()
-        };
-        let abi = if self.sig.abi.is_some() {
-            format!("{} ", self.sig.abi.to_token_stream())
-        } else {
-            String::new()
+#[cfg(debug_assertions)]
+impl FnSignatureFormat for ImplItemFn {
+    fn format(&self) -> (usize, String) {
+        let mut tokens = self.vis.to_token_stream();
+        self.defaultness.to_tokens(&mut tokens);
+        self.sig.to_tokens(&mut tokens);
+        let open_brace = self.block.brace_token.span.open().start();
+        layout_tokens(tokens, open_brace, "{").unwrap_or_else(|| {
+            let defaultness = if self.defaultness.is_some() {
+                "default "
+            } else {
+                ""
+            };
+            (
+                self.sig.ident.span().start().line,
+                format!("{defaultness}{}", format_signature(&self.vis, &self.sig)),
+            )
+        })
+    }
+}
+
+#[cfg(debug_assertions)]
+impl FnSignatureFormat for TraitItemFn {
+    fn format(&self) -> (usize, String) {
+        let tokens = self.sig.to_token_stream();
+        // Only default methods have a body, required methods end with a semicolon
+        let (trailing_location, trailing) = match (&self.default, &self.semi_token) {
+            (Some(block), _) => (block.brace_token.span.open().start(), "{"),
+            (None, Some(semi_token)) => (semi_token.span.start(), ";"),
+            (None, None) => (self.sig.span().end(), ""),
         };
-        let fn_token = self.sig.fn_token.to_token_stream();
-        let ident = self.sig.ident.to_token_stream();
-        let generics = self.sig.generics.to_token_stream().remove_whitespace();
-
-        let sig_end_line = &self.sig.span().end().line;
-        let output_end_line = &self.sig.output.span().end().line;
-        let block_brace_start_line = &self.block.brace_token.span.open().start().line;
-        let output_brace_difference = block_brace_start_line - output_end_line;
-        let output_newline_brace = if output_brace_difference > 0 {
-            "\n".repeat(output_brace_difference)
-        } else {
-            String::new()
+        layout_tokens(tokens, trailing_location, trailing).unwrap_or_else(|| {
+            let mut text = format_signature(&Visibility::Inherited, &self.sig);
+            if self.default.is_none() {
+                text.pop();
+                text = format!("{};", text.trim_end());
+            }
+            (self.sig.ident.span().start().line, text)
+        })
+    }
+}
+
+#[cfg(debug_assertions)]
+impl FnSignatureFormat for ExprClosure {
+    fn format(&self) -> (usize, String) {
+        let mut tokens = TokenStream::new();
+        self.lifetimes.to_tokens(&mut tokens);
+        self.constness.to_tokens(&mut tokens);
+        self.movability.to_tokens(&mut tokens);
+        self.asyncness.to_tokens(&mut tokens);
+        self.capture.to_tokens(&mut tokens);
+        self.or1_token.to_tokens(&mut tokens);
+        self.inputs.to_tokens(&mut tokens);
+        self.or2_token.to_tokens(&mut tokens);
+        self.output.to_tokens(&mut tokens);
+        // Closures with a block body end the header at its opening brace, expression bodies are
+        // captured as parser statements themselves
+        let (trailing_location, trailing) = match self.body.as_ref() {
+            Expr::Block(expr_block) => (expr_block.block.brace_token.span.open().start(), "{"),
+            body => (body.span().start(), ""),
         };
-        // This is brittle, but no other quick solutions present themselves at this time
-        let inputs = self
-            .sig
-            .inputs
-            .to_token_stream()
-            .to_string()
-            .replace("& '", "&'")
-            .replace(" :", ":");
-        let output = self
-            .sig
-            .output
-            .to_token_stream()
-            .to_string()
-            .replace(" < & ", "<&")
-            .replace("& '", "&'")
-            .replace(" >", ">")
-            .replace("< '", "<'");
-
-        format!(
-            "{vis} {constness}{asyncness}{unsafety}{abi}{fn_token} {ident}{generics}({inputs}) {output}{output_newline_brace} {{"
+        // Closures are usually passed inline, e.g. `with_context("…", |input| {`, so the header is
+        // shown without the indentation up to its column
+        let laid_out = layout_tokens(tokens, trailing_location, trailing)
+            .map(|(line, text)| (line, dedent(&text)));
+        laid_out.unwrap_or_else(|| {
+            let header = ExprClosure {
+                attrs: Vec::new(),
+                body: Box::new(syn::parse_quote!({})),
+                ..self.clone()
+            };
+            let item: Item = syn::parse_quote!(const _: () = #header;);
+            let printed = format_item_header(item);
+            let closure_text = printed
+                .trim_start_matches("const _: () = ")
+                .trim_end_matches(';')
+                .trim_end_matches('}')
+                .trim_end();
+            let closure_text = if trailing.is_empty() {
+                closure_text.trim_end_matches('{').trim_end()
+            } else {
+                closure_text
+            };
+            (self.or1_token.span.start().line, closure_text.to_string())
+        })
+    }
+}
+
+#[cfg(debug_assertions)]
+impl FnSignatureFormat for ItemImpl {
+    fn format(&self) -> (usize, String) {
+        let mut tokens = TokenStream::new();
+        self.defaultness.to_tokens(&mut tokens);
+        self.unsafety.to_tokens(&mut tokens);
+        self.impl_token.to_tokens(&mut tokens);
+        self.generics.to_tokens(&mut tokens);
+        if let Some((bang, path, for_token)) = &self.trait_ {
+            bang.to_tokens(&mut tokens);
+            path.to_tokens(&mut tokens);
+            for_token.to_tokens(&mut tokens);
+        }
+        self.self_ty.to_tokens(&mut tokens);
+        self.generics.where_clause.to_tokens(&mut tokens);
+        let open_brace = self.brace_token.span.open().start();
+        layout_tokens(tokens, open_brace, "{").unwrap_or_else(|| {
+            let header = ItemImpl {
+                attrs: Vec::new(),
+                items: Vec::new(),
+                ..self.clone()
+            };
+            (
+                self.impl_token.span.start().line,
+                format_item_header(Item::Impl(header)),
+            )
+        })
+    }
+}
+
+#[cfg(debug_assertions)]
+impl FnSignatureFormat for ItemTrait {
+    fn format(&self) -> (usize, String) {
+        let mut tokens = self.vis.to_token_stream();
+        self.unsafety.to_tokens(&mut tokens);
+        self.auto_token.to_tokens(&mut tokens);
+        self.trait_token.to_tokens(&mut tokens);
+        self.ident.to_tokens(&mut tokens);
+        self.generics.to_tokens(&mut tokens);
+        self.colon_token.to_tokens(&mut tokens);
+        self.supertraits.to_tokens(&mut tokens);
+        self.generics.where_clause.to_tokens(&mut tokens);
+        let open_brace = self.brace_token.span.open().start();
+        layout_tokens(tokens, open_brace, "{").unwrap_or_else(|| {
+            let header = ItemTrait {
+                attrs: Vec::new(),
+                items: Vec::new(),
+                ..self.clone()
+            };
+            (
+                self.trait_token.span.start().line,
+                format_item_header(Item::Trait(header)),
+            )
+        })
+    }
+}
+
+#[cfg(debug_assertions)]
+/// Renders `tokens` on a single line the way they were written, or as `prettyplease` would print them
+/// as a type when span locations aren't available.
+fn format_tokens(tokens: &impl ToTokens) -> String {
+    let tokens = tokens.to_token_stream();
+    let end = tokens
+        .clone()
+        .into_iter()
+        .last()
+        .map(|token| token.span().end());
+    end.and_then(|end| layout_tokens(tokens.clone(), end, ""))
+        .filter(|(_, text)| !text.contains('\n'))
+        .map_or_else(
+            || {
+                let item: Item = syn::parse_quote!(type T = #tokens;);
+                let printed = format_item_header(item);
+                printed
+                    .trim_start_matches("type T = ")
+                    .trim_end_matches(';')
+                    .to_string()
+            },
+            |(_, text)| text.trim().to_string(),
         )
+}
+
+#[cfg(debug_assertions)]
+/// Lays `tokens`, followed by `trailing` at `trailing_location`, out at the lines and columns their
+/// spans point to. Returns `None` when span locations aren't available, in which case every location
+/// is reported as line 0, or the spans don't move strictly forwards.
+fn layout_tokens(
+    tokens: TokenStream,
+    trailing_location: LineColumn,
+    trailing: &str,
+) -> Option<(usize, String)> {
+    fn flatten(tokens: TokenStream, placed: &mut Vec<(LineColumn, String)>) {
+        for token in tokens {
+            match token {
+                TokenTree::Group(group) => {
+                    let (open, close) = match group.delimiter() {
+                        Delimiter::Parenthesis => ("(", ")"),
+                        Delimiter::Brace => ("{", "}"),
+                        Delimiter::Bracket => ("[", "]"),
+                        Delimiter::None => ("", ""),
+                    };
+                    if !open.is_empty() {
+                        placed.push((group.span_open().start(), open.to_string()));
+                    }
+                    flatten(group.stream(), placed);
+                    if !close.is_empty() {
+                        placed.push((group.span_close().start(), close.to_string()));
+                    }
+                }
+                token => placed.push((token.span().start(), token.to_string())),
+            }
+        }
     }
+
+    let mut placed = Vec::new();
+    flatten(tokens, &mut placed);
+    placed.push((trailing_location, trailing.to_string()));
+
+    let first_line = placed.first()?.0.line;
+    // Tokens created by `quote!` or parsed without location information all share a single span
+    if first_line == 0 || placed.windows(2).any(|pair| pair[1].0 <= pair[0].0) {
+        return None;
+    }
+
+    let mut text = String::new();
+    let mut line = first_line;
+    let mut column = placed.first()?.0.column;
+    text.push_str(&" ".repeat(column));
+    for (location, token_text) in placed {
+        if location.line > line {
+            text.push_str(&"\n".repeat(location.line - line));
+            line = location.line;
+            column = 0;
+        }
+        text.push_str(&" ".repeat(location.column.saturating_sub(column)));
+        text.push_str(&token_text);
+        column = location.column.max(column) + token_text.chars().count();
+        line += token_text.matches('\n').count();
+    }
+    Some((first_line, text))
+}
+
+#[cfg(debug_assertions)]
+/// Removes the indentation all non-blank lines of `text` share.
+fn dedent(text: &str) -> String {
+    let indent = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start_matches(' ').len())
+        .min()
+        .unwrap_or_default();
+    text.lines()
+        .map(|line| line.get(indent..).unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(debug_assertions)]
+fn format_signature(vis: &Visibility, sig: &Signature) -> String {
+    format_item_header(Item::Fn(ItemFn {
+        attrs: Vec::new(),
+        vis: vis.clone(),
+        sig: sig.clone(),
+        block: Box::new(Block {
+            brace_token: Brace::default(),
+            stmts: Vec::new(),
+        }),
+    }))
 }
 
+/// Prints an item with an empty body through `prettyplease`, dropping the body's closing brace.
+#[cfg(debug_assertions)]
+fn format_item_header(item: Item) -> String {
+    let printed = prettyplease::unparse(&File {
+        shebang: None,
+        attrs: Vec::new(),
+        items: vec![item],
+    });
+    let printed = printed.trim_end();
+    printed
+        .strip_suffix('}')
+        .unwrap_or(printed)
+        .trim_end()
+        .to_string()
+}
+
+#[allow(clippy::result_large_err)]
 pub fn map_parser_err<'a, Input, Output, ParserType, MapFn>(
     mut parser: ParserType,
     mut f: MapFn,
@@ -538,3 +1851,372 @@ where
 {
     move |input: Input| parser.parse(input).map_err(&mut f)
 }
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use super::*;
+
+    fn parse_capture(source: &str) -> Option<ParserSourceCapture> {
+        ParserSourceCapture::from_expr(&syn::parse_str::<Expr>(source).unwrap())
+    }
+
+    #[test]
+    fn from_expr_captures_parser_invocations() {
+        let capture = parse_capture(r#"tag("world")(input)?"#).unwrap();
+        assert_eq!(capture.ident.source_text, "tag");
+        assert_eq!(capture.pattern_text(), Some(r#""world""#));
+        assert_eq!(capture.input_name(), Some("input"));
+    }
+
+    #[test]
+    fn from_expr_captures_parse_method_chains() {
+        let capture = parse_capture(r#"tag("a").and(tag("b")).parse(input)"#).unwrap();
+        assert_eq!(capture.ident.source_text, "tag");
+        assert_eq!(capture.pattern_text(), Some(r#"("a").and(tag("b"))"#));
+    }
+
+    #[test]
+    fn from_expr_rejects_constructors() {
+        for source in [
+            "Ok((input, value))",
+            "Some(value)",
+            "String::from(value)",
+            "Vec::with_capacity(capacity)",
+            "Header(input)",
+        ] {
+            assert!(parse_capture(source).is_none(), "{source} is not a parser");
+        }
+        assert!(parse_capture("Self::header(input)").is_some());
+    }
+
+    #[test]
+    fn from_expr_takes_the_pattern_from_the_tokens_after_the_ident() {
+        let capture = parse_capture(r#"(tag("x"))(input)"#).unwrap();
+        assert_eq!(capture.ident.source_text, "tag");
+        assert_eq!(capture.pattern_text(), Some(r#""x""#));
+
+        let capture = parse_capture(r#"tag ("x")(input)"#).unwrap();
+        assert_eq!(capture.pattern_text(), Some(r#""x""#));
+    }
+
+    #[test]
+    fn pattern_text_drops_the_trailing_argument_comma() {
+        let capture = parse_capture("tag(\n    KEYWORD,\n)(input)").unwrap();
+        assert_eq!(capture.pattern_text(), Some("KEYWORD"));
+
+        let capture = parse_capture("tag((\"a\", \"b\",))(input)").unwrap();
+        assert_eq!(capture.pattern_text(), Some("(\"a\", \"b\",)"));
+    }
+
+    #[test]
+    fn from_expr_keeps_annotate_wrappers_as_written() {
+        let capture = parse_capture(r#"annotate(tag("world"))(input)?"#).unwrap();
+        assert_eq!(capture.ident.source_text, "tag");
+        assert_eq!(capture.pattern_text(), Some(r#""world""#));
+        let (merged, spans) = capture.merged_source_spans().unwrap();
+        assert_eq!(merged.source_text, r#"annotate(tag("world"))(input)"#);
+        assert_eq!(spans.statement, 0..29);
+        assert_eq!(spans.ident, Some(9..12));
+        assert_eq!(spans.pattern, Some(12..21));
+        assert_eq!(spans.input, Some(22..29));
+
+        let capture = parse_capture("annotate(\n    tag(\"a\"),\n)(input)").unwrap();
+        let merged = capture.merged_source().unwrap();
+        assert_eq!(merged.source_text, "annotate(\n    tag(\"a\"),\n)(input)");
+    }
+
+    #[test]
+    fn from_expr_captures_method_parsers() {
+        let capture = parse_capture("self.header(input)?").unwrap();
+        assert_eq!(capture.ident.source_text, "self.header");
+        assert_eq!(capture.pattern_text(), None);
+        assert_eq!(capture.input_name(), Some("input"));
+
+        assert!(parse_capture("self.header()").is_none());
+        assert!(parse_capture("input.split_at(3)").is_none());
+    }
+
+    #[test]
+    fn from_local_captures_the_binding_pattern() {
+        let syn::Stmt::Local(local) =
+            syn::parse_str::<syn::Stmt>(r#"let (input, key) = alpha1(input)?;"#).unwrap()
+        else {
+            panic!("not a let statement");
+        };
+        let capture = ParserSourceCapture::from_local(&local).unwrap();
+        assert_eq!(
+            capture.binding_pattern.unwrap().source_text,
+            "let (input, key) = "
+        );
+        assert_eq!(capture.ident.source_text, "alpha1");
+    }
+
+    #[test]
+    fn strip_call_parens_strips_a_single_pair() {
+        assert_eq!(strip_call_parens(r#"("world")"#), r#""world""#);
+        assert_eq!(strip_call_parens(" (input) "), "input");
+        assert_eq!(strip_call_parens("input"), "input");
+    }
+
+    #[test]
+    fn strip_call_parens_keeps_separate_pairs() {
+        assert_eq!(strip_call_parens("(a)(b)"), "(a)(b)");
+    }
+
+    #[test]
+    fn strip_call_parens_ignores_parens_in_literals() {
+        assert_eq!(strip_call_parens(r#"(")")"#), r#"")""#);
+        assert_eq!(strip_call_parens(r#"("(")"#), r#""(""#);
+        assert_eq!(strip_call_parens("('(')"), "'('");
+        assert_eq!(strip_call_parens(r#"("\")")"#), r#""\")""#);
+    }
+
+    #[test]
+    fn with_file_source_clamps_to_the_first_line() {
+        let captured = ParserStatementsCapture {
+            source: SourceCapture {
+                source_text: "    b".to_string(),
+                line_number: 2,
+                ..SourceCapture::default()
+            },
+            ..ParserStatementsCapture::default()
+        };
+        let with_file_source = captured
+            .with_file_source("a\n    b\nc", 5, 0..=usize::MAX)
+            .unwrap();
+        assert_eq!(with_file_source.source.source_text, "a\n    b\nc");
+        assert_eq!(with_file_source.source.line_number, 1);
+        assert_eq!(with_file_source.source.end_line_number, Some(3));
+    }
+
+    #[test]
+    fn with_file_source_keeps_within_bounds() {
+        let captured = ParserStatementsCapture {
+            source: SourceCapture {
+                source_text: "c".to_string(),
+                line_number: 3,
+                ..SourceCapture::default()
+            },
+            ..ParserStatementsCapture::default()
+        };
+        let with_file_source = captured
+            .with_file_source("a\nb\nc\nd\ne", 1, 3..=4)
+            .unwrap();
+        assert_eq!(with_file_source.source.source_text, "c\nd");
+    }
+
+    #[test]
+    fn with_file_source_tells_missing_locations_from_changed_files() {
+        let captured = statements(&["b(i)?;"], 0);
+        assert_eq!(
+            captured.with_file_source("a\nb(i)?;", 1, 0..=usize::MAX),
+            Err(FileSourceMismatch::Changed)
+        );
+
+        let mut without_location = captured.clone();
+        without_location.source.line_number = 0;
+        assert_eq!(
+            without_location.with_file_source("b(i)?;", 1, 0..=usize::MAX),
+            Err(FileSourceMismatch::NoLocation)
+        );
+
+        let mut unchanged = captured;
+        unchanged.source.line_number = 2;
+        assert!(unchanged
+            .with_file_source("a\nb(i)?;", 1, 0..=usize::MAX)
+            .is_ok());
+    }
+
+    fn piece(source_text: &str, line_number: usize, start_column: usize) -> SourceCapture {
+        SourceCapture {
+            source_text: source_text.to_string(),
+            line_number,
+            start_column: Some(start_column),
+            end_column: Some(start_column + source_text.len()),
+            ..SourceCapture::default()
+        }
+    }
+
+    #[test]
+    fn merge_source_indents_the_first_piece_to_its_column() {
+        let mut merged = SourceCapture::default();
+        merged.merge_source(&piece("tag", 3, 4));
+        assert_eq!(merged.source_text, "    tag");
+        assert_eq!(merged.line_number, 3);
+        assert_eq!(merged.end_line_number, Some(3));
+        assert_eq!(merged.start_column, Some(4));
+        assert_eq!(merged.end_column, Some(7));
+        assert_eq!(merged.span_length, Some(7));
+    }
+
+    #[test]
+    fn merge_source_concatenates_pieces_on_the_same_line() {
+        let mut merged = SourceCapture::default();
+        merged
+            .merge_source(&piece("tag", 3, 4))
+            .merge_source(&piece(r#"("a")"#, 3, 7));
+        assert_eq!(merged.source_text, r#"    tag("a")"#);
+        assert_eq!(merged.end_line_number, Some(3));
+        assert_eq!(merged.end_column, Some(12));
+        assert_eq!(merged.span_length, Some(12));
+    }
+
+    #[test]
+    fn merge_source_starts_later_pieces_on_their_own_line() {
+        let mut merged = SourceCapture::default();
+        merged
+            .merge_source(&piece("tag(", 3, 4))
+            .merge_source(&piece(r#""a""#, 5, 8));
+        assert_eq!(merged.source_text, "    tag(\n\n        \"a\"");
+        assert_eq!(merged.line_number, 3);
+        assert_eq!(merged.end_line_number, Some(5));
+        assert_eq!(merged.start_column, Some(4));
+        assert_eq!(merged.end_column, Some(11));
+        assert!(merged.is_multiline());
+    }
+
+    #[test]
+    fn merge_source_skips_pieces_without_a_column() {
+        let mut merged = SourceCapture::default();
+        merged.merge_source(&SourceCapture {
+            source_text: "tag".to_string(),
+            ..SourceCapture::default()
+        });
+        assert_eq!(merged, SourceCapture::default());
+    }
+
+    fn statements(lines: &[&str], failure_index: usize) -> ParserStatementsCapture {
+        let mut statement_spans = Vec::new();
+        let mut start = 0;
+        for line in lines {
+            statement_spans.push(StatementSpans {
+                statement: start..start + line.len(),
+                ..StatementSpans::default()
+            });
+            start += line.len() + 1;
+        }
+        ParserStatementsCapture {
+            source: SourceCapture {
+                source_text: lines.join("\n"),
+                line_number: 10,
+                end_line_number: Some(10 + lines.len() - 1),
+                ..SourceCapture::default()
+            },
+            statement_spans,
+            failure_index,
+        }
+    }
+
+    #[test]
+    fn window_keeps_the_lines_around_the_failing_statement() {
+        let captured = statements(&["a(i)?;", "b(i)?;", "c(i)?;", "d(i)?;", "e(i)?;"], 2);
+        let window = captured.window(1);
+        assert_eq!(window.source.source_text, "b(i)?;\nc(i)?;\nd(i)?;");
+        assert_eq!(window.source.line_number, 11);
+        assert_eq!(window.source.end_line_number, Some(13));
+        assert_eq!(window.source.span_length, Some(20));
+        let statement_spans = window
+            .statement_spans
+            .iter()
+            .map(|spans| spans.statement.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(statement_spans, [0..6, 7..13, 14..20]);
+        assert_eq!(window.failure_index, 1);
+    }
+
+    #[test]
+    fn window_stops_at_the_ends_of_the_source() {
+        let captured = statements(&["a(i)?;", "b(i)?;", "c(i)?;"], 0);
+        let window = captured.window(1);
+        assert_eq!(window.source.source_text, "a(i)?;\nb(i)?;");
+        assert_eq!(window.source.line_number, 10);
+        assert_eq!(window.failure_index, 0);
+
+        let window = captured.window(10);
+        assert_eq!(window.source.source_text, captured.source.source_text);
+        assert_eq!(window.statement_spans, captured.statement_spans);
+    }
+
+    #[test]
+    fn window_drops_statements_that_are_cut_off() {
+        let mut captured = statements(&["a(", "i)?;", "b(i)?;", "c(i)?;"], 2);
+        captured.statement_spans.remove(1);
+        captured.statement_spans[0].statement = 0..7;
+        captured.failure_index = 1;
+        let window = captured.window(1);
+        assert_eq!(window.source.source_text, "i)?;\nb(i)?;\nc(i)?;");
+        assert_eq!(window.statement_spans.len(), 2);
+        assert_eq!(window.statement_spans[0].statement, 5..11);
+        assert_eq!(window.failure_index, 0);
+    }
+
+    #[test]
+    fn window_without_a_failing_statement_keeps_everything() {
+        let captured = statements(&["a(i)?;", "b(i)?;"], 5);
+        assert_eq!(captured.window(0), captured);
+    }
+
+    #[test]
+    fn dedent_removes_the_shared_indentation() {
+        assert_eq!(dedent("        |input| {"), "|input| {");
+        assert_eq!(dedent("    |a,\n\n      b| {"), "|a,\n\n  b| {");
+    }
+
+    #[test]
+    fn closure_headers_are_shown_from_their_first_token() {
+        let statement = syn::parse_str::<syn::Stmt>(
+            "let parser = with_context(\"grammar::key\", |input| {\n    alpha1(input)\n});",
+        )
+        .unwrap();
+        let syn::Stmt::Local(local) = statement else {
+            panic!("not a let statement");
+        };
+        let Expr::Call(call) = *local.init.unwrap().expr else {
+            panic!("not a call");
+        };
+        let Expr::Closure(closure) = &call.args[1] else {
+            panic!("not a closure");
+        };
+        assert_eq!(closure.format(), (1, "|input| {".to_string()));
+    }
+
+    fn end_of(tokens: &TokenStream) -> LineColumn {
+        tokens.clone().into_iter().last().unwrap().span().end()
+    }
+
+    #[test]
+    fn layout_tokens_keeps_wrapped_lines_and_columns() {
+        let tokens = "    tag(\n        \"a\",\n    )"
+            .parse::<TokenStream>()
+            .unwrap();
+        let trailing_location = end_of(&tokens);
+        let (first_line, text) = layout_tokens(tokens, trailing_location, "(input)").unwrap();
+        assert_eq!(first_line, 1);
+        assert_eq!(text, "    tag(\n        \"a\",\n    )(input)");
+    }
+
+    #[test]
+    fn layout_tokens_keeps_the_spacing_between_tokens() {
+        let tokens = "tag ( \"a\" )".parse::<TokenStream>().unwrap();
+        let trailing_location = LineColumn {
+            line: 1,
+            column: 12,
+        };
+        let (_, text) = layout_tokens(tokens, trailing_location, "?").unwrap();
+        assert_eq!(text, "tag ( \"a\" ) ?");
+    }
+
+    #[test]
+    fn layout_tokens_needs_span_locations() {
+        let tokens = quote::quote!(tag("a"));
+        let trailing_location = LineColumn { line: 1, column: 0 };
+        assert_eq!(layout_tokens(tokens, trailing_location, "(input)"), None);
+    }
+
+    #[test]
+    fn layout_tokens_needs_forward_spans() {
+        let tokens = "tag(\"a\")".parse::<TokenStream>().unwrap();
+        let trailing_location = LineColumn { line: 1, column: 2 };
+        assert_eq!(layout_tokens(tokens, trailing_location, "(input)"), None);
+    }
+}
@@ -0,0 +1,242 @@
+//! Combinators that record a failing parser's error and keep parsing, so that every error in the
+//! input can be reported at once:
+//!
+//! ```ignore
+//! let diagnostics = Diagnostics::new();
+//! let (_, entries) = many0(terminated(
+//!     skip_to(entry, ";", &diagnostics),
+//!     tag(";"),
+//! ))(source)?;
+//! eprintln!("{diagnostics:?}");
+//! ```
+//!
+//! `nom::Err::Error` and `nom::Err::Failure` are recovered from, while `nom::Err::Incomplete` is passed
+//! on since more input may still make the parser succeed. Nothing is left to skip at the end of the
+//! input, so errors there are passed on as well, letting repeating combinators like `many0` stop.
+
+use crate::ContextError;
+use nom::{IResult, Parser};
+use std::{
+    cell::RefCell,
+    fmt::{Debug, Display, Formatter},
+};
+
+/// The errors collected by the recovery combinators, rendered in the order they occur in the input.
+#[derive(Clone, Default)]
+pub struct Diagnostics<'a> {
+    pub errors: RefCell<Vec<ContextError<'a>>>,
+}
+
+impl<'a> Diagnostics<'a> {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&self, error: ContextError<'a>) {
+        self.errors.borrow_mut().push(error);
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.errors.borrow().len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.errors.borrow().is_empty()
+    }
+
+    /// The collected errors in source order.
+    ///
+    /// Every error's input is the remainder of the same source when it failed, so the longer the
+    /// remainder, the earlier the error. Errors without an input are kept at the end.
+    #[must_use]
+    pub fn into_errors(self) -> Vec<ContextError<'a>> {
+        let mut errors = self.errors.into_inner();
+        errors.sort_by_key(|error| std::cmp::Reverse(error.input.map(str::len)));
+        errors
+    }
+}
+
+impl<'a> Debug for Diagnostics<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let errors = self.clone().into_errors();
+        for (index, error) in errors.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{error:?}")?;
+        }
+        match errors.len() {
+            0 => Ok(()),
+            1 => writeln!(f, "\nfound 1 error"),
+            count => writeln!(f, "\nfound {count} errors"),
+        }
+    }
+}
+
+impl<'a> Display for Diagnostics<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(self, f)
+    }
+}
+
+/// Runs `parser` and, when it fails, records the error and skips the input up to the next `sync`
+/// token, which is left in the input for the surrounding grammar to consume. Without another `sync`
+/// token the rest of the input is skipped.
+///
+/// It has to be followed by a parser consuming the `sync` token, e.g. `terminated(skip_to(entry, ";",
+/// &diagnostics), tag(";"))`: a failure right before the token skips nothing, which repeating
+/// combinators like `many0` report as an error when `skip_to` is repeated on its own.
+#[allow(clippy::result_large_err)]
+pub fn skip_to<'a, 'd, Output, ParserType>(
+    mut parser: ParserType,
+    sync: &'a str,
+    diagnostics: &'d Diagnostics<'a>,
+) -> impl FnMut(&'a str) -> IResult<&'a str, Option<Output>, ContextError<'a>> + 'd
+where
+    'a: 'd,
+    ParserType: Parser<&'a str, Output, ContextError<'a>> + 'd,
+{
+    move |input: &'a str| match parser.parse(input) {
+        Ok((remaining, output)) => Ok((remaining, Some(output))),
+        Err(nom::Err::Error(error) | nom::Err::Failure(error)) if !input.is_empty() => {
+            diagnostics.push(error);
+            let skipped = input.find(sync).unwrap_or(input.len());
+            Ok((&input[skipped..], None))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Runs `parser` and, when it fails, records the error and skips the rest of the current line,
+/// including its line ending.
+#[allow(clippy::result_large_err)]
+pub fn skip_line<'a, 'd, Output, ParserType>(
+    mut parser: ParserType,
+    diagnostics: &'d Diagnostics<'a>,
+) -> impl FnMut(&'a str) -> IResult<&'a str, Option<Output>, ContextError<'a>> + 'd
+where
+    'a: 'd,
+    ParserType: Parser<&'a str, Output, ContextError<'a>> + 'd,
+{
+    move |input: &'a str| match parser.parse(input) {
+        Ok((remaining, output)) => Ok((remaining, Some(output))),
+        Err(nom::Err::Error(error) | nom::Err::Failure(error)) if !input.is_empty() => {
+            diagnostics.push(error);
+            let skipped = input.find('\n').map_or(input.len(), |newline| newline + 1);
+            Ok((&input[skipped..], None))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Runs `parser` and, when it fails, records the error and produces `placeholder` without consuming
+/// any input, as if the missing piece had been there.
+#[allow(clippy::result_large_err)]
+pub fn insert_placeholder<'a, 'd, Output, ParserType>(
+    mut parser: ParserType,
+    placeholder: Output,
+    diagnostics: &'d Diagnostics<'a>,
+) -> impl FnMut(&'a str) -> IResult<&'a str, Output, ContextError<'a>> + 'd
+where
+    'a: 'd,
+    Output: Clone + 'd,
+    ParserType: Parser<&'a str, Output, ContextError<'a>> + 'd,
+{
+    move |input: &'a str| match parser.parse(input) {
+        Err(nom::Err::Error(error) | nom::Err::Failure(error)) => {
+            diagnostics.push(error);
+            Ok((input, placeholder.clone()))
+        }
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nom::{
+        bytes::complete::tag,
+        character::complete::{alpha1, digit1},
+        multi::many0,
+        sequence::{separated_pair, terminated},
+    };
+
+    #[allow(clippy::result_large_err)]
+    fn entry(input: &str) -> IResult<&str, (&str, &str), ContextError<'_>> {
+        separated_pair(alpha1, tag("="), digit1)(input)
+    }
+
+    #[test]
+    fn skip_to_recovers_at_the_sync_token() {
+        let diagnostics = Diagnostics::new();
+        let (remaining, entries) =
+            many0(terminated(skip_to(entry, ";", &diagnostics), tag(";")))("a=1;b=x;;c=3;")
+                .unwrap();
+        assert_eq!(remaining, "");
+        assert_eq!(entries, [Some(("a", "1")), None, None, Some(("c", "3"))]);
+        let errors = diagnostics.into_errors();
+        assert_eq!(
+            errors.iter().map(|error| error.input).collect::<Vec<_>>(),
+            [Some("x;;c=3;"), Some(";c=3;")]
+        );
+    }
+
+    #[test]
+    fn skip_to_passes_on_errors_at_the_end_of_the_input() {
+        let diagnostics = Diagnostics::new();
+        assert!(skip_to(entry, ";", &diagnostics)("").is_err());
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn skip_line_skips_the_line_ending() {
+        let diagnostics = Diagnostics::new();
+        let (remaining, entries) =
+            many0(skip_line(terminated(entry, tag("\n")), &diagnostics))("a=1\nb\nc=3\n").unwrap();
+        assert_eq!(remaining, "");
+        assert_eq!(entries, [Some(("a", "1")), None, Some(("c", "3"))]);
+        assert_eq!(diagnostics.len(), 1);
+    }
+
+    #[test]
+    fn insert_placeholder_consumes_nothing() {
+        let diagnostics = Diagnostics::new();
+        let (remaining, value) = insert_placeholder(digit1, "0", &diagnostics)(";").unwrap();
+        assert_eq!((remaining, value), (";", "0"));
+        assert_eq!(diagnostics.len(), 1);
+    }
+
+    #[test]
+    fn into_errors_sorts_by_position() {
+        let diagnostics = Diagnostics::new();
+        for input in ["c", "abc", "bc"] {
+            let mut error = ContextError::default();
+            error.set_input(input);
+            diagnostics.push(error);
+        }
+        diagnostics.push(ContextError::default());
+        assert_eq!(
+            diagnostics
+                .into_errors()
+                .iter()
+                .map(|error| error.input)
+                .collect::<Vec<_>>(),
+            [Some("abc"), Some("bc"), Some("c"), None]
+        );
+    }
+
+    #[test]
+    fn incomplete_is_passed_on() {
+        let diagnostics = Diagnostics::new();
+        let mut incomplete = skip_to(
+            nom::bytes::streaming::tag::<_, _, ContextError>("abc"),
+            ";",
+            &diagnostics,
+        );
+        assert!(matches!(incomplete("ab"), Err(nom::Err::Incomplete(_))));
+        assert!(diagnostics.is_empty());
+    }
+}
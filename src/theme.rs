@@ -0,0 +1,156 @@
+use anstyle::{AnsiColor, Color, Style};
+use std::{fmt::Display, str::FromStr};
+
+/// The styles used when rendering a `ContextError` annotation.
+///
+/// `error`, `info`, `note`, `help` and `line_number` are handed to the `annotate_snippets` renderer, while
+/// `found`, `expected` and `parser_name` wrap the text we compose ourselves for labels and footers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Theme {
+    pub error: Style,
+    pub info: Style,
+    pub note: Style,
+    pub help: Style,
+    pub line_number: Style,
+    pub found: Style,
+    pub expected: Style,
+    pub parser_name: Style,
+}
+
+impl Theme {
+    /// The colors used by `rustc` diagnostics, with bright red line numbers.
+    #[must_use]
+    pub const fn standard() -> Self {
+        Self {
+            error: fg(AnsiColor::BrightRed).bold(),
+            info: fg(AnsiColor::BrightBlue).bold(),
+            note: fg(AnsiColor::BrightGreen).bold(),
+            help: fg(AnsiColor::BrightCyan).bold(),
+            line_number: fg(AnsiColor::BrightRed),
+            found: fg(AnsiColor::Yellow),
+            expected: fg(AnsiColor::Green),
+            parser_name: fg(AnsiColor::Cyan).bold(),
+        }
+    }
+
+    /// Bold, bright foregrounds with the found/expected text inverted so it stands out on any background.
+    #[must_use]
+    pub const fn high_contrast() -> Self {
+        Self {
+            error: fg(AnsiColor::BrightRed).bold().underline(),
+            info: fg(AnsiColor::BrightWhite).bold(),
+            note: fg(AnsiColor::BrightWhite).bold(),
+            help: fg(AnsiColor::BrightWhite).bold(),
+            line_number: fg(AnsiColor::BrightWhite).bold(),
+            found: fg(AnsiColor::BrightYellow).bold().invert(),
+            expected: fg(AnsiColor::BrightCyan).bold().invert(),
+            parser_name: fg(AnsiColor::BrightWhite).bold().underline(),
+        }
+    }
+
+    /// Avoids pairing red with green; found/expected are distinguished by orange/blue hues and effects.
+    #[must_use]
+    pub const fn colorblind_safe() -> Self {
+        Self {
+            error: fg(AnsiColor::BrightYellow).bold(),
+            info: fg(AnsiColor::BrightBlue).bold(),
+            note: fg(AnsiColor::BrightCyan).bold(),
+            help: fg(AnsiColor::BrightMagenta).bold(),
+            line_number: fg(AnsiColor::BrightBlue),
+            found: fg(AnsiColor::Yellow).underline(),
+            expected: fg(AnsiColor::Blue).bold(),
+            parser_name: fg(AnsiColor::BrightMagenta).bold(),
+        }
+    }
+
+    /// No styling at all, for logs and terminals that don't support ANSI escapes.
+    #[must_use]
+    pub const fn plain() -> Self {
+        Self {
+            error: Style::new(),
+            info: Style::new(),
+            note: Style::new(),
+            help: Style::new(),
+            line_number: Style::new(),
+            found: Style::new(),
+            expected: Style::new(),
+            parser_name: Style::new(),
+        }
+    }
+
+    /// Whether the theme styles nothing, in which case errors are rendered without any ANSI escapes.
+    #[must_use]
+    pub fn is_plain(&self) -> bool {
+        *self == Self::plain()
+    }
+
+    #[must_use]
+    pub const fn from_preset(preset: ThemePreset) -> Self {
+        match preset {
+            ThemePreset::Standard => Self::standard(),
+            ThemePreset::HighContrast => Self::high_contrast(),
+            ThemePreset::ColorblindSafe => Self::colorblind_safe(),
+            ThemePreset::Plain => Self::plain(),
+        }
+    }
+
+    #[must_use]
+    pub fn paint_found(&self, text: impl Display) -> String {
+        paint(self.found, text)
+    }
+
+    #[must_use]
+    pub fn paint_expected(&self, text: impl Display) -> String {
+        paint(self.expected, text)
+    }
+
+    #[must_use]
+    pub fn paint_parser_name(&self, text: impl Display) -> String {
+        paint(self.parser_name, text)
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self::standard()
+    }
+}
+
+impl From<ThemePreset> for Theme {
+    fn from(preset: ThemePreset) -> Self {
+        Self::from_preset(preset)
+    }
+}
+
+/// The built-in themes, parseable from strings such as `"high-contrast"` so they can be chosen from an
+/// environment variable or a config file at runtime.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ThemePreset {
+    #[default]
+    Standard,
+    HighContrast,
+    ColorblindSafe,
+    Plain,
+}
+
+impl FromStr for ThemePreset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().replace('_', "-").as_str() {
+            "default" | "standard" => Ok(Self::Standard),
+            "high-contrast" => Ok(Self::HighContrast),
+            "colorblind-safe" | "colourblind-safe" => Ok(Self::ColorblindSafe),
+            "plain" | "none" => Ok(Self::Plain),
+            other => Err(format!("unknown theme preset `{other}`")),
+        }
+    }
+}
+
+const fn fg(color: AnsiColor) -> Style {
+    Style::new().fg_color(Some(Color::Ansi(color)))
+}
+
+fn paint(style: Style, text: impl Display) -> String {
+    format!("{style}{text}{style:#}")
+}
@@ -1,35 +1,188 @@
 use crate::ContextError;
 #[cfg(debug_assertions)]
 use crate::{FunctionContext, ParserSourceCapture, SourceCapture};
+#[cfg(debug_assertions)]
+use proc_macro2::Span;
+use proc_macro2::TokenStream;
+#[cfg(debug_assertions)]
+use quote::quote_spanned;
 use quote::{quote, ToTokens};
+#[cfg(debug_assertions)]
+use std::cell::RefCell;
+use syn::{parse_quote, Path};
+
+#[cfg(debug_assertions)]
+thread_local! {
+    static SPAN_REGISTRY: RefCell<Option<SpanRegistry>> = const { RefCell::new(None) };
+}
+
+/// The spans captures were built from during the current expansion, and how their locations are
+/// emitted.
+#[cfg(debug_assertions)]
+struct SpanRegistry {
+    location_mode: LocationMode,
+    spans: Vec<Span>,
+}
+
+/// Where the line and column numbers of generated `SourceCapture`s come from. Falling back needs the
+/// span a capture was built from, so the mode only applies to captures built within
+/// [`with_span_registry`], while the others always emit the locations read from the spans.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LocationMode {
+    /// Use the span locations when they are available and fall back to `line!()` and `column!()`
+    /// for captures whose location reads as line 0, which is what `proc_macro2` reports inside
+    /// proc-macro expansions when the compiler doesn't expose span locations.
+    #[default]
+    Auto,
+    /// Always emit the locations read from the spans.
+    SpanLocations,
+    /// Always emit `line!()` and `column!()` spanned to the captured tokens, so the compiler fills in
+    /// the locations of the expanded code.
+    Fallback,
+}
+
+#[cfg(debug_assertions)]
+impl LocationMode {
+    fn falls_back(self, line_number: usize) -> bool {
+        match self {
+            Self::Auto => line_number == 0,
+            Self::SpanLocations => false,
+            Self::Fallback => true,
+        }
+    }
+}
+
+/// Runs `expand` with a fresh registry of the spans captures are built from, which lets the
+/// `SourceCapture`s generated within it take their locations as `location_mode` says.
+///
+/// The annotating macro wraps each expansion in it, so neither the registry nor the mode outlive the
+/// expansion they were set up for, even when a proc-macro server reuses its thread. Spans captured
+/// outside of it, e.g. at runtime, aren't registered.
+pub fn with_span_registry<T>(location_mode: LocationMode, expand: impl FnOnce() -> T) -> T {
+    #[cfg(debug_assertions)]
+    {
+        let outer = SPAN_REGISTRY.replace(Some(SpanRegistry {
+            location_mode,
+            spans: Vec::new(),
+        }));
+        let expanded = expand();
+        SPAN_REGISTRY.set(outer);
+        expanded
+    }
+    #[cfg(not(debug_assertions))]
+    {
+        let _ = location_mode;
+        expand()
+    }
+}
+
+/// Remembers `span` until the end of the current [`with_span_registry`] scope, returning the index
+/// the generated code looks it up by, or `None` outside of one.
+#[cfg(debug_assertions)]
+pub(crate) fn register_span(span: Span) -> Option<usize> {
+    SPAN_REGISTRY.with_borrow_mut(|registry| {
+        let spans = &mut registry.as_mut()?.spans;
+        spans.push(span);
+        Some(spans.len() - 1)
+    })
+}
+
+/// The span registered at `span_index` when the current [`LocationMode`] falls back for a capture
+/// whose location reads as `line_number`.
+#[cfg(debug_assertions)]
+fn fallback_span(span_index: usize, line_number: usize) -> Option<Span> {
+    SPAN_REGISTRY.with_borrow(|registry| {
+        let registry = registry.as_ref()?;
+        registry
+            .location_mode
+            .falls_back(line_number)
+            .then(|| registry.spans.get(span_index).copied())
+            .flatten()
+    })
+}
+
+/// Generates the code constructing a value, referring to this crate by `crate_path`.
+///
+/// The `ToTokens` implementations refer to this crate as `::nom_plus`, while crates that rename
+/// `nom_plus` or re-export it pass their own path, e.g.
+/// `error.to_tokens_with(&parse_quote!(::my_crate::nom_plus), &mut tokens)`.
+pub trait ToTokensWith {
+    fn to_tokens_with(&self, crate_path: &Path, tokens: &mut TokenStream);
+
+    fn to_token_stream_with(&self, crate_path: &Path) -> TokenStream {
+        let mut tokens = TokenStream::new();
+        self.to_tokens_with(crate_path, &mut tokens);
+        tokens
+    }
+}
+
+/// Interpolates `value` into `quote!` with the paths to this crate starting at `crate_path`.
+pub struct WithCratePath<'t, T> {
+    pub value: &'t T,
+    pub crate_path: &'t Path,
+}
+
+impl<'t, T> WithCratePath<'t, T> {
+    #[must_use]
+    pub fn new(value: &'t T, crate_path: &'t Path) -> Self {
+        Self { value, crate_path }
+    }
+}
+
+impl<'t, T: ToTokensWith> ToTokens for WithCratePath<'t, T> {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        self.value.to_tokens_with(self.crate_path, tokens);
+    }
+}
+
+/// The path the generated code refers to this crate by unless told otherwise.
+#[must_use]
+pub fn default_crate_path() -> Path {
+    parse_quote!(::nom_plus)
+}
+
 impl<'a> ToTokens for ContextError<'a> {
-    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
-        let message = self
-            .message
-            .map_or_else(|| quote! {None}, |message| quote! {Some(#message)});
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        self.to_tokens_with(&default_crate_path(), tokens);
+    }
+}
+
+impl<'a> ToTokensWith for ContextError<'a> {
+    fn to_tokens_with(&self, crate_path: &Path, tokens: &mut TokenStream) {
+        let message = self.message.map_or_else(
+            || quote! { ::core::option::Option::None },
+            |message| quote! { ::core::option::Option::Some(#message) },
+        );
 
         #[cfg(debug_assertions)]
-        let context = self.as_ref().context();
+        let context = WithCratePath::new(self.as_ref().context(), crate_path);
 
-        let input = self
-            .input
-            .map_or_else(|| quote! {None}, |input| quote! {Some(#input)});
+        let input = self.input.map_or_else(
+            || quote! { ::core::option::Option::None },
+            |input| quote! { ::core::option::Option::Some(#input) },
+        );
 
         #[cfg(debug_assertions)]
         tokens.extend(quote! {
-            ContextError {
+            #crate_path::ContextError {
                 message: #message,
                 context: #context,
-                file: Some(file!()),
+                file: ::core::option::Option::Some(::core::file!()),
                 input: #input,
+                chain: ::std::vec::Vec::new(),
+                pattern_value: ::core::option::Option::None,
+                captured_bindings: ::std::vec::Vec::new(),
+                parser_name: ::core::option::Option::None,
+                expectation: ::core::option::Option::None,
+                label: ::core::option::Option::None,
+                help: ::core::option::Option::None,
             }
         });
 
         #[cfg(not(debug_assertions))]
         tokens.extend(quote! {
-            ContextError {
+            #crate_path::ContextError {
                 message: #message,
-                file: Some(file!()),
                 input: #input,
             }
         });
@@ -37,33 +190,50 @@ impl<'a> ToTokens for ContextError<'a> {
 }
 #[cfg(debug_assertions)]
 impl ToTokens for FunctionContext {
-    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
-        let signature = self.signature();
-        let parser_contexts = self.parser_contexts.as_ref().map_or_else(
-            || quote! { None },
-            |parser_contexts| quote! {Some(vec![#(#parser_contexts,)*])},
-        );
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        self.to_tokens_with(&default_crate_path(), tokens);
+    }
+}
 
-        let parser_context_failure_index = self
-            .parser_context_failure_index
-            .map_or_else(|| quote! { None }, |index| quote! { Some(#index)});
+#[cfg(debug_assertions)]
+impl ToTokensWith for FunctionContext {
+    fn to_tokens_with(&self, crate_path: &Path, tokens: &mut TokenStream) {
+        let signature = WithCratePath::new(self.signature(), crate_path);
+        let enclosing_item = option_tokens(self.enclosing_item.as_ref(), crate_path);
+        let self_type = self.self_type.as_ref().map_or_else(
+            || quote! { ::core::option::Option::None },
+            |self_type| quote! { ::core::option::Option::Some(::std::string::String::from(#self_type))},
+        );
+        let closure = option_tokens(self.closure.as_ref(), crate_path);
+        let parser_contexts = vec_option_tokens(self.parser_contexts.as_ref(), crate_path);
 
-        let nested_parser_contexts = self.nested_parser_contexts.as_ref().map_or_else(
-            || quote! { None },
-            |nested_parser_contexts| quote! {Some(vec![#(#nested_parser_contexts,)*])},
+        let parser_context_failure_index = self.parser_context_failure_index.map_or_else(
+            || quote! { ::core::option::Option::None },
+            |index| quote! { ::core::option::Option::Some(#index)},
         );
 
-        let closing_tokens = self.closing_tokens.as_ref().map_or_else(
-            || quote! { None },
-            |closing_tokens| quote! { Some(#closing_tokens)},
+        let nested_parser_contexts =
+            vec_option_tokens(self.nested_parser_contexts.as_ref(), crate_path);
+
+        let nested_parser_failure_index = self.nested_parser_failure_index.map_or_else(
+            || quote! { ::core::option::Option::None },
+            |index| quote! { ::core::option::Option::Some(#index)},
         );
 
+        let closing_tokens = option_tokens(self.closing_tokens.as_ref(), crate_path);
+
         tokens.extend(quote! {
-            FunctionContext{
+            #crate_path::FunctionContext {
                 signature: #signature,
+                enclosing_item: #enclosing_item,
+                self_type: #self_type,
+                closure: #closure,
+                // Only known at runtime, see `FunctionContext::set_instantiated_at`
+                instantiated_at: ::core::option::Option::None,
                 parser_contexts: #parser_contexts,
                 parser_context_failure_index: #parser_context_failure_index,
                 nested_parser_contexts: #nested_parser_contexts,
+                nested_parser_failure_index: #nested_parser_failure_index,
                 closing_tokens: #closing_tokens,
             }
         });
@@ -72,61 +242,192 @@ impl ToTokens for FunctionContext {
 
 #[cfg(debug_assertions)]
 impl ToTokens for SourceCapture {
-    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
-        let source_text = &self.source_text;
-        let line_number = self.line_number;
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        self.to_tokens_with(&default_crate_path(), tokens);
+    }
+}
 
-        let start_column = self.start_column.map_or_else(
-            || quote! { None },
-            |start_column| quote! {Some(#start_column)},
-        );
+#[cfg(debug_assertions)]
+impl ToTokensWith for SourceCapture {
+    fn to_tokens_with(&self, crate_path: &Path, tokens: &mut TokenStream) {
+        let source_text = &self.source_text;
 
-        let end_column = self
-            .end_column
-            .map_or_else(|| quote! { None }, |end_column| quote! { Some(#end_column)});
+        let fallback_span = self
+            .span_index
+            .and_then(|span_index| fallback_span(span_index, self.line_number));
+        let (line_number, end_line_number, start_column, end_column) = match fallback_span {
+            Some(span) => self.fallback_locations(span),
+            None => self.span_locations(),
+        };
 
-        let span_length = self.end_column.map_or_else(
-            || quote! { None },
-            |span_length| quote! { Some(#span_length)},
+        let span_length = self.span_length.map_or_else(
+            || quote! { ::core::option::Option::None },
+            |span_length| quote! { ::core::option::Option::Some(#span_length)},
         );
 
         tokens.extend(quote! {
-            SourceCapture {
-                source_text: #source_text.to_string(),
+            #crate_path::SourceCapture {
+                source_text: ::std::string::String::from(#source_text),
                 line_number: #line_number,
+                end_line_number: #end_line_number,
                 start_column: #start_column,
                 end_column: #end_column,
                 span_length: #span_length,
+                span_index: ::core::option::Option::None,
             }
         });
     }
 }
 
 #[cfg(debug_assertions)]
-impl ToTokens for ParserSourceCapture {
-    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
-        let binding_pattern = self.binding_pattern().as_ref().map_or_else(
-            || quote! {None},
-            |binding_pattern| quote! {Some(#binding_pattern)},
+impl SourceCapture {
+    /// The line number, end line number, start column and end column as read from the spans.
+    fn span_locations(&self) -> (TokenStream, TokenStream, TokenStream, TokenStream) {
+        let line_number = self.line_number;
+
+        let end_line_number = self.end_line_number.map_or_else(
+            || quote! { ::core::option::Option::None },
+            |end_line_number| quote! { ::core::option::Option::Some(#end_line_number)},
         );
-        // dbg!("WHAT HERE", &let_binding_pattern);
-        let ident = self.ident();
-        let pattern = self.pattern();
-        let nested_parsers = self.nested_parsers().as_ref().map_or_else(
-            || quote! { None },
-            |nested_parsers| quote! {Some(vec![#(#nested_parsers,)*])},
+
+        let start_column = self.start_column.map_or_else(
+            || quote! { ::core::option::Option::None },
+            |start_column| quote! { ::core::option::Option::Some(#start_column)},
+        );
+
+        let end_column = self.end_column.map_or_else(
+            || quote! { ::core::option::Option::None },
+            |end_column| quote! { ::core::option::Option::Some(#end_column)},
+        );
+
+        (
+            quote! { #line_number },
+            end_line_number,
+            start_column,
+            end_column,
+        )
+    }
+
+    /// Like [`SourceCapture::span_locations`] with the start of the capture taken from `line!()` and
+    /// `column!()` invoked at `span` and the end derived from the captured text.
+    fn fallback_locations(
+        &self,
+        span: Span,
+    ) -> (TokenStream, TokenStream, TokenStream, TokenStream) {
+        let line_number = quote_spanned! {span=> ::core::line!() as usize };
+        // `column!()` is 1-based while span locations are 0-based
+        let start_column = quote_spanned! {span=> ::core::column!() as usize - 1 };
+
+        let line_count = self.source_text.matches('\n').count();
+        let end_line_number = self.end_line_number.map_or_else(
+            || quote! { ::core::option::Option::None },
+            |_| quote! { ::core::option::Option::Some(#line_number + #line_count)},
+        );
+
+        let end_column = self.end_column.map_or_else(
+            || quote! { ::core::option::Option::None },
+            |_| match self.source_text.rsplit_once('\n') {
+                Some((_, last_line)) => {
+                    let end_column = last_line.len();
+                    quote! { ::core::option::Option::Some(#end_column)}
+                }
+                None => {
+                    let length = self.source_text.len();
+                    quote! { ::core::option::Option::Some(#start_column + #length)}
+                }
+            },
+        );
+
+        let start_column = self.start_column.map_or_else(
+            || quote! { ::core::option::Option::None },
+            |_| quote! { ::core::option::Option::Some(#start_column)},
         );
 
-        let input = self.input();
+        (line_number, end_line_number, start_column, end_column)
+    }
+}
+
+#[cfg(debug_assertions)]
+impl ToTokens for ParserSourceCapture {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        self.to_tokens_with(&default_crate_path(), tokens);
+    }
+}
+
+#[cfg(debug_assertions)]
+impl ToTokensWith for ParserSourceCapture {
+    fn to_tokens_with(&self, crate_path: &Path, tokens: &mut TokenStream) {
+        let binding_pattern = option_tokens(self.binding_pattern().as_ref(), crate_path);
+        // dbg!("WHAT HERE", &let_binding_pattern);
+        let ident = WithCratePath::new(self.ident(), crate_path);
+        let pattern = WithCratePath::new(self.pattern(), crate_path);
+        let nested_parsers = vec_option_tokens(self.nested_parsers().as_ref(), crate_path);
+
+        let input = WithCratePath::new(self.input(), crate_path);
+        let expression = option_tokens(self.expression.as_ref(), crate_path);
 
         tokens.extend(quote! {
-        ParserSourceCapture {
+        #crate_path::ParserSourceCapture {
             binding_pattern: #binding_pattern,
             ident: #ident,
             pattern: #pattern,
             nested_parsers: #nested_parsers,
             input: #input,
+            expression: #expression,
         }
         });
     }
 }
+
+/// `Some(value)` or `None`, with `value` referring to this crate by `crate_path`.
+#[cfg(debug_assertions)]
+fn option_tokens<T: ToTokensWith>(value: Option<&T>, crate_path: &Path) -> TokenStream {
+    value.map_or_else(
+        || quote! { ::core::option::Option::None },
+        |value| {
+            let value = WithCratePath::new(value, crate_path);
+            quote! { ::core::option::Option::Some(#value) }
+        },
+    )
+}
+
+/// `Some(vec![..])` or `None`, with the values referring to this crate by `crate_path`.
+#[cfg(debug_assertions)]
+fn vec_option_tokens<T: ToTokensWith>(values: Option<&Vec<T>>, crate_path: &Path) -> TokenStream {
+    values.map_or_else(
+        || quote! { ::core::option::Option::None },
+        |values| {
+            let values = values
+                .iter()
+                .map(|value| WithCratePath::new(value, crate_path));
+            quote! { ::core::option::Option::Some(::std::vec![#(#values,)*]) }
+        },
+    )
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use super::*;
+
+    fn capture_tokens() -> String {
+        let mut capture = SourceCapture {
+            source_text: "tag".to_string(),
+            line_number: 3,
+            ..SourceCapture::default()
+        };
+        capture.set_span(Span::call_site());
+        capture.to_token_stream().to_string()
+    }
+
+    #[test]
+    fn location_mode_only_applies_within_its_registry() {
+        let fallback = with_span_registry(LocationMode::Fallback, capture_tokens);
+        assert!(fallback.contains("line !"), "{fallback}");
+
+        let span_locations = with_span_registry(LocationMode::SpanLocations, capture_tokens);
+        assert!(!span_locations.contains("line !"), "{span_locations}");
+
+        let outside = capture_tokens();
+        assert!(!outside.contains("line !"), "{outside}");
+    }
+}
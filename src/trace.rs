@@ -0,0 +1,391 @@
+//! Opt-in tracing of the path a parse took through the annotated functions and combinators.
+//!
+//! With tracing enabled, every traced parser records when it is entered, at which offset of the input,
+//! and whether it succeeded. Once the outermost traced parser returns, the calls are printed to stderr
+//! as an indented tree:
+//!
+//! ```text
+//! → key_value:12 at 0
+//!   → key:16 at 0
+//!   ← key:16 ok at 0, consumed 3
+//!   → separator:20 at 3
+//!   ← separator:20 error at 3
+//! ← key_value:12 error at 0
+//! ```
+//!
+//! Offsets are measured from the start of the first input seen since tracing was enabled, so the
+//! parsers a plain function wraps in `annotate` one after the other, each printed as a tree of its own,
+//! still report where in the input they ran. A traced parser called with a longer input than that
+//! starts measuring from its input instead.
+//!
+//! Tracing only happens in debug builds, in release builds the traced parsers are run as they are.
+//!
+//! With the `tracing` feature, annotated parsers additionally enter a `tracing` span at the `DEBUG`
+//! level while they run, in debug and release builds alike. Functions annotated with
+//! `#[annotate_error]` run their body through [`observe_function!`](crate::observe_function), which
+//! names the span after the function and records the `file` and `line` it is defined at. Span names
+//! have to be known at compile time, so the parsers wrapped at runtime by `annotate`, `Annotated` and
+//! `with_context` enter a span called `parser` instead, carrying the function's name in its `name`
+//! field. A parser returning a `ContextError` emits an event with the `found` input and, when known,
+//! what was `expected`.
+
+use crate::config::render_config;
+use crate::profile::{self, profiling, ProfileKey};
+use crate::ContextError;
+use nom::{IResult, InputLength};
+use std::{
+    cell::RefCell,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+static TRACING: AtomicBool = AtomicBool::new(false);
+
+/// Incremented whenever tracing is enabled, so every thread measures offsets from a new input.
+static TRACING_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static TRACE: RefCell<Trace> = const { RefCell::new(Trace::new()) };
+}
+
+/// The calls recorded on the current thread since the outermost traced parser was entered.
+struct Trace {
+    depth: usize,
+    /// The length of the first input seen since tracing was enabled, which offsets are measured
+    /// against.
+    root_length: usize,
+    /// The `TRACING_GENERATION` `root_length` was measured in.
+    generation: usize,
+    lines: Vec<String>,
+}
+
+impl Trace {
+    const fn new() -> Self {
+        Self {
+            depth: 0,
+            root_length: 0,
+            generation: 0,
+            lines: Vec::new(),
+        }
+    }
+
+    fn push_line(&mut self, line: String) {
+        self.lines
+            .push(format!("{}{line}", "  ".repeat(self.depth)));
+    }
+}
+
+/// Whether parser calls are currently traced.
+#[must_use]
+pub fn tracing() -> bool {
+    TRACING.load(Ordering::Relaxed)
+}
+
+/// Enables or disables tracing for every thread. Enabling it again measures offsets from the next
+/// input traced.
+pub fn set_tracing(enabled: bool) {
+    if enabled {
+        TRACING_GENERATION.fetch_add(1, Ordering::Relaxed);
+    }
+    TRACING.store(enabled, Ordering::Relaxed);
+}
+
+/// Runs `parse` on `input`, recording the call as `name` defined at `line` when tracing is enabled.
+///
+/// Annotated functions pass their `FunctionContext::qualified_name` and signature line, combinators
+/// the name they were given.
+pub fn trace<Input, Output, Error>(
+    name: &str,
+    line: Option<usize>,
+    input: Input,
+    parse: impl FnOnce(Input) -> IResult<Input, Output, Error>,
+) -> IResult<Input, Output, Error>
+where
+    Input: InputLength,
+{
+    if !cfg!(debug_assertions) || !tracing() {
+        return parse(input);
+    }
+
+    let theme = render_config().theme;
+    let name = match line {
+        Some(line) => theme.paint_parser_name(format!("{name}:{line}")),
+        None => theme.paint_parser_name(name),
+    };
+    let input_length = input.input_len();
+    let generation = TRACING_GENERATION.load(Ordering::Relaxed);
+    let offset = TRACE.with_borrow_mut(|trace| {
+        // An input longer than the first one can't be a remainder of it
+        if trace.generation != generation || input_length > trace.root_length {
+            trace.root_length = input_length;
+            trace.generation = generation;
+        }
+        let offset = trace.root_length.saturating_sub(input_length);
+        trace.push_line(format!("→ {name} at {offset}"));
+        trace.depth += 1;
+        offset
+    });
+
+    let result = parse(input);
+
+    TRACE.with_borrow_mut(|trace| {
+        trace.depth -= 1;
+        let outcome = match &result {
+            Ok((remaining, _)) => format!(
+                "ok at {offset}, consumed {}",
+                input_length.saturating_sub(remaining.input_len())
+            ),
+            Err(nom::Err::Error(_)) => format!("error at {offset}"),
+            Err(nom::Err::Failure(_)) => format!("failure at {offset}"),
+            Err(nom::Err::Incomplete(_)) => format!("incomplete at {offset}"),
+        };
+        trace.push_line(format!("← {name} {outcome}"));
+        if trace.depth == 0 {
+            eprintln!("{}", std::mem::take(&mut trace.lines).join("\n"));
+        }
+    });
+    result
+}
+
+/// How an observed parser appears in the execution trace, `tracing` spans and profiles.
+pub(crate) struct Description {
+    pub name: String,
+    /// What the parser's profile is reported as, e.g. the function's path or, for parsers wrapped at
+    /// runtime, `function@file:line`.
+    pub function: String,
+    /// The file and line of the function's signature.
+    #[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+    pub file: Option<&'static str>,
+    pub line: Option<usize>,
+}
+
+impl Description {
+    /// Describes a parser that is a function of its own, like the ones wrapped in `with_context`.
+    pub(crate) fn function(
+        function: impl Into<String>,
+        file: Option<&'static str>,
+        line: Option<usize>,
+    ) -> Self {
+        let function = function.into();
+        Self {
+            name: function.clone(),
+            function,
+            file,
+            line,
+        }
+    }
+}
+
+/// The `tracing` span an observed parser enters while it runs. It is empty without the `tracing`
+/// feature.
+pub struct FunctionSpan {
+    #[cfg(feature = "tracing")]
+    span: ::tracing::Span,
+}
+
+impl FunctionSpan {
+    /// A span that isn't entered, used by [`function_span!`](crate::function_span) without the
+    /// `tracing` feature.
+    #[doc(hidden)]
+    #[must_use]
+    pub fn none() -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            span: ::tracing::Span::none(),
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[doc(hidden)]
+    #[must_use]
+    pub fn new(span: ::tracing::Span) -> Self {
+        Self { span }
+    }
+
+    /// The span of a parser whose name is only known at runtime.
+    pub(crate) fn parser(description: &Description) -> Self {
+        #[cfg(feature = "tracing")]
+        return Self::new(::tracing::debug_span!(
+            "parser",
+            name = %description.name,
+            file = description.file,
+            line = description.line
+        ));
+        #[cfg(not(feature = "tracing"))]
+        {
+            let _ = description;
+            Self::none()
+        }
+    }
+}
+
+/// Runs the body of the function `$name` on `$input` as [`observe_function`] does, entering a
+/// `tracing` span named `$name` with the `tracing` feature. `$name` has to be a constant, e.g. a string
+/// literal or `concat!(module_path!(), "::header")`.
+///
+/// This is what `#[annotate_error]` wraps the body of an annotated function in:
+///
+/// ```ignore
+/// fn header(input: &str) -> IResult<&str, &str, ContextError> {
+///     nom_plus::observe_function!("header", input, |input| {
+///         let (input, _) = tag("[")(input)?;
+///         terminated(alpha1, tag("]"))(input)
+///     })
+/// }
+/// ```
+#[macro_export]
+macro_rules! observe_function {
+    ($name:expr, $input:expr, $parse:expr $(,)?) => {
+        $crate::trace::observe_function(
+            $name,
+            ::core::file!(),
+            ::core::line!() as usize,
+            || $crate::function_span!($name),
+            $input,
+            $parse,
+        )
+    };
+}
+
+/// Creates the [`FunctionSpan`] of the function `$name`, entered at the `DEBUG` level.
+#[cfg(feature = "tracing")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! function_span {
+    ($name:expr) => {
+        $crate::trace::FunctionSpan::new($crate::__tracing::debug_span!(
+            $name,
+            file = ::core::file!(),
+            line = ::core::line!()
+        ))
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! function_span {
+    ($name:expr) => {
+        $crate::trace::FunctionSpan::none()
+    };
+}
+
+/// Runs `parse`, the body of the annotated function `name` defined at `file` and `line`, on `input`,
+/// recording it in the execution trace when tracing is enabled, in its profile when profiling is
+/// enabled and in the span returned by `span` with the `tracing` feature.
+///
+/// Prefer [`observe_function!`](crate::observe_function), which fills in the location and names the
+/// span after the function.
+#[allow(clippy::result_large_err)]
+pub fn observe_function<'a, Input, Output>(
+    name: &'static str,
+    file: &'static str,
+    line: usize,
+    span: impl FnOnce() -> FunctionSpan,
+    input: Input,
+    parse: impl FnOnce(Input) -> IResult<Input, Output, ContextError<'a>>,
+) -> IResult<Input, Output, ContextError<'a>>
+where
+    Input: InputLength,
+{
+    observe(
+        ProfileKey::Function(name),
+        || Description::function(name, Some(file), Some(line)),
+        |_| span(),
+        input,
+        parse,
+    )
+}
+
+/// Runs the annotated parser `parse` on `input`, recording it in the execution trace when tracing is
+/// enabled, in its profile when profiling is enabled and in a `tracing` span with the `tracing`
+/// feature.
+///
+/// The parser is profiled as `key`. `describe` and `span` are only called when the parser is traced or
+/// a span is entered, and otherwise `describe` only for the first call of `key` while profiling.
+#[allow(clippy::result_large_err)]
+pub(crate) fn observe<'a, Input, Output>(
+    key: ProfileKey,
+    describe: impl Fn() -> Description,
+    span: impl FnOnce(&Description) -> FunctionSpan,
+    input: Input,
+    parse: impl FnOnce(Input) -> IResult<Input, Output, ContextError<'a>>,
+) -> IResult<Input, Output, ContextError<'a>>
+where
+    Input: InputLength,
+{
+    let traced = cfg!(debug_assertions) && tracing();
+    let profiled = profiling();
+    #[cfg(feature = "tracing")]
+    let spanned = ::tracing::enabled!(::tracing::Level::DEBUG);
+    #[cfg(not(feature = "tracing"))]
+    let spanned = false;
+    if !traced && !profiled && !spanned {
+        return parse(input);
+    }
+
+    let description = (traced || spanned).then(&describe);
+    #[cfg(feature = "tracing")]
+    let _entered = description
+        .as_ref()
+        .filter(|_| spanned)
+        .map(|description| span(description).span.entered());
+    #[cfg(not(feature = "tracing"))]
+    let _ = span;
+
+    let parse = |input| match description.as_ref().filter(|_| traced) {
+        Some(description) => trace(&description.name, description.line, input, parse),
+        None => parse(input),
+    };
+    let result = if profiled {
+        let name = || {
+            description.as_ref().map_or_else(
+                || describe().function,
+                |description| description.function.clone(),
+            )
+        };
+        profile::record(key, name, || parse(input))
+    } else {
+        parse(input)
+    };
+
+    #[cfg(feature = "tracing")]
+    if let (Some(description), Err(nom::Err::Error(error) | nom::Err::Failure(error))) =
+        (description.as_ref().filter(|_| spanned), &result)
+    {
+        let found = render_config().truncate(error.input.unwrap_or_default());
+        ::tracing::debug!(
+            found = &*found,
+            expected = expected(error),
+            "{} failed",
+            description.name
+        );
+    }
+    result
+}
+
+/// What the failing parser of `error` expected, in the order the rendered error prefers them.
+#[cfg(feature = "tracing")]
+fn expected(error: &ContextError) -> Option<String> {
+    #[cfg(debug_assertions)]
+    {
+        let failing_parser = error.context.failing_nested_parser_context().or_else(|| {
+            error
+                .context
+                .parser_contexts
+                .as_ref()?
+                .get(error.context.failure_index())
+        });
+        error.expectation.map(str::to_string).or_else(|| {
+            let parser = failing_parser?;
+            parser
+                .pattern_text()
+                .or_else(|| Some(parser.ident.source_text.trim()).filter(|ident| !ident.is_empty()))
+                .map(str::to_string)
+        })
+    }
+    #[cfg(not(debug_assertions))]
+    {
+        let _ = error;
+        None
+    }
+}